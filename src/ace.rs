@@ -1,9 +1,12 @@
 use crate::anki::{AnkiConnect, Media};
 use crate::{
     anki::NoteData,
+    cedict,
     config::Config,
-    dict::{lookup, DictDb},
+    dict::{group_by_sequence, lookup, segment, DbDictEntry, DbTag, DictDb},
     media::{fetch_audio_server, forvo, get_sent, google_img},
+    pitch::{render_pitch, PitchDisplay},
+    yomichan::escape_html,
     CONFIG,
 };
 use anyhow::{anyhow, Context, Result};
@@ -13,7 +16,8 @@ use lazy_static::lazy_static;
 use pinyin::{to_pinyin_vec, Pinyin};
 use pinyin_parser::PinyinParser;
 use regex::Regex;
-use std::{collections::HashMap, convert::TryInto, io::Write};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::{convert::TryInto, io::Write};
 use std::{fs, path::Path};
 
 pub fn get_config() -> Result<&'static Config> {
@@ -34,15 +38,51 @@ pub fn read_words_file(path: &Path) -> Result<Vec<(String, String)>> {
     Ok(word_sentence_pairs)
 }
 
-fn pinyin_from_definition(meaning: &String) -> Option<Vec<String>> {
+// Sentences of a text, cut after sentence ending punctuation and line breaks
+pub fn split_sentences(text: &str) -> Vec<&str> {
+    text.split_inclusive(|c| "。！？!?\n".contains(c))
+        .map(str::trim)
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+// Returns [(word, sentence)] for each term found in the text, in the order they first appear.
+// Terms ranked more common than min_rank or rarer than max_rank are left out, and
+// max_rank leaves out terms no frequency list knows too
+pub fn scan_text(
+    dict_db: &DictDb,
+    text: &str,
+    min_rank: Option<i64>,
+    max_rank: Option<i64>,
+) -> Result<Vec<(String, String)>> {
+    let mut word_sentence_pairs: Vec<(String, String)> = vec![];
+    let mut seen = HashSet::new();
+    for sentence in split_sentences(text) {
+        for term in segment(dict_db, sentence)? {
+            let result = &term.results[0];
+            let rank = result.entries.iter().filter_map(|entry| entry.rank).min();
+            let in_range = match rank {
+                Some(rank) => {
+                    min_rank.map_or(true, |min| rank >= min)
+                        && max_rank.map_or(true, |max| rank <= max)
+                }
+                None => max_rank.is_none(),
+            };
+            if in_range && seen.insert(result.term.clone()) {
+                word_sentence_pairs.push((result.term.clone(), sentence.to_string()));
+            }
+        }
+    }
+    Ok(word_sentence_pairs)
+}
+
+fn pinyin_from_definition(meaning: &str) -> Option<Vec<String>> {
     lazy_static! {
         static ref RE: Regex = Regex::new(r"\w\s\[(.*)\]").unwrap();
     }
     if let Some(captures) = RE.captures(meaning) {
         let pinyin = captures.get(0).unwrap().as_str().to_string();
-        let pinyin = PinyinParser::strict(&pinyin)
-            .into_iter()
-            .collect::<Vec<_>>();
+        let pinyin = PinyinParser::strict(&pinyin).collect::<Vec<_>>();
 
         let syllables = [
             (1, ['ā', 'ē', 'ī', 'ō', 'ū', 'ǖ']),
@@ -91,20 +131,143 @@ fn pinyin_from_definition(meaning: &String) -> Option<Vec<String>> {
     None
 }
 
-#[cfg(test)]
-mod tests {
-    use super::pinyin_from_definition;
-    #[test]
-    fn it_works() {
-        assert_eq!(
-            pinyin_from_definition(&"俄罗斯 [éluósi]".to_string()),
-            Some(vec![
-                "e2".to_string(),
-                "luo2".to_string(),
-                "si5".to_string()
-            ])
+fn format_tags(tags: &[DbTag]) -> String {
+    tags.iter()
+        .map(|tag| {
+            format!(
+                "<span class=\"tag\" data-category=\"{}\" title=\"{}\">{}</span>",
+                escape_html(&tag.category),
+                escape_html(&tag.notes),
+                escape_html(&tag.name)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Each term gets its own block, with its senses numbered and tagged
+fn format_meanings(dict_db: &DictDb, defs: &[DbDictEntry]) -> Result<String> {
+    let mut dict_tags: HashMap<i64, HashMap<String, DbTag>> = HashMap::new();
+    let mut terms = vec![];
+    for group in group_by_sequence(defs) {
+        let mut senses = vec![];
+        let mut last: Option<&DbDictEntry> = None;
+        for def in group {
+            let meaning = def.meaning.replace("\n", "<br>");
+            // glosses of the same sense are listed together and share its tags, rows
+            // imported without a sense id are matched on their tags instead
+            if let Some(last) = last {
+                let same_sense = match (last.sense, def.sense) {
+                    (Some(a), Some(b)) => a == b,
+                    (None, None) => last.definition_tags == def.definition_tags,
+                    _ => false,
+                };
+                if same_sense {
+                    senses.push(meaning);
+                    continue;
+                }
+            }
+            last = Some(def);
+
+            let known = match dict_tags.entry(def.dict_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(dict_db.get_dict_tags(def.dict_id)?),
+            };
+            let tags = def
+                .definition_tags
+                .split_whitespace()
+                .map(|name| {
+                    // tags without any metadata are still worth showing
+                    known.get(name).cloned().unwrap_or_else(|| DbTag {
+                        name: name.to_string(),
+                        category: String::new(),
+                        notes: String::new(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            if tags.is_empty() {
+                senses.push(meaning);
+            } else {
+                senses.push(format!("{} {}", format_tags(&tags), meaning));
+            }
+        }
+
+        if senses.len() == 1 {
+            terms.push(senses.remove(0));
+        } else {
+            let items = senses
+                .iter()
+                .map(|sense| format!("<li>{}</li>", sense))
+                .collect::<String>();
+            terms.push(format!("<ol>{}</ol>", items));
+        }
+    }
+    Ok(terms.join("<br><br>"))
+}
+
+// One line per kanji in the word with its readings, meanings and stats such as stroke count
+fn kanji_breakdown(dict_db: &DictDb, word: &str) -> Result<String> {
+    let mut lines = vec![];
+    // the stats of every kanji are described by the tags of its dictionary
+    let mut dict_tags = HashMap::new();
+    for character in word.chars() {
+        let kanji = match dict_db.lookup_kanji(&character.to_string())? {
+            Some(kanji) => kanji,
+            None => continue,
+        };
+        let known = match dict_tags.entry(kanji.dict_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(dict_db.get_dict_tags(kanji.dict_id)?),
+        };
+
+        let mut stats = vec![];
+        for (name, value) in &kanji.stats {
+            let value = value
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| value.to_string());
+            // yomichan's "misc" stats hold the readable bits (strokes, grade, jlpt...)
+            match known.get(name) {
+                Some(tag) if tag.category == "misc" => {
+                    let label = if tag.notes.is_empty() {
+                        &tag.name
+                    } else {
+                        &tag.notes
+                    };
+                    stats.push(format!("{}: {}", escape_html(label), escape_html(&value)));
+                }
+                _ => {}
+            }
+        }
+
+        let mut line = format!(
+            "<span class=\"kanji\">{}</span> <span class=\"onyomi\">{}</span> <span class=\"kunyomi\">{}</span> {}",
+            escape_html(&kanji.character),
+            escape_html(&kanji.onyomi),
+            escape_html(&kanji.kunyomi),
+            escape_html(&kanji.meanings.join(", "))
         );
+        if !stats.is_empty() {
+            line += &format!(" <span class=\"stats\">({})</span>", stats.join(", "));
+        }
+        lines.push(line);
     }
+    Ok(lines.join("<br>"))
+}
+
+fn pitch_accent(dict_db: &DictDb, def: &DbDictEntry, display: PitchDisplay) -> Result<String> {
+    // kana only entries may leave their reading empty
+    let reading = if def.reading.is_empty() {
+        &def.expression
+    } else {
+        &def.reading
+    };
+    let pitches = dict_db
+        .lookup_pitch(&def.expression, reading)?
+        .into_iter()
+        .map(|position| render_pitch(reading, position, display))
+        .collect::<Vec<_>>();
+    Ok(pitches.join("<br>"))
 }
 
 pub async fn package_card(
@@ -122,17 +285,16 @@ pub async fn package_card(
     };
 
     let defs = &lookup(dict_db, word.to_string())
-        .with_context(|| "Failed to lookup word in dictionary")?;
+        .with_context(|| "Failed to lookup word in dictionary")?
+        .into_iter()
+        .flat_map(|result| result.entries)
+        .collect::<Vec<_>>();
 
     if defs.is_empty() {
         return Ok(None);
     }
 
-    let meaning = defs
-        .iter()
-        .map(|def| def.meaning.replace("\n", "<br>"))
-        .collect::<Vec<String>>()
-        .join("<br><br>");
+    let meaning = format_meanings(dict_db, defs)?;
 
     let image_res = if config.media.add_picture {
         google_img(word.to_string(), config.is_japanese)
@@ -169,7 +331,9 @@ pub async fn package_card(
 
     let word_pinyin = if !config.is_japanese {
         let tone_num = ['1', '2', '3', '4'];
-        let pinyin_vec = if let Some(pinyin_vec) = pinyin_from_definition(&meaning) {
+        let pinyin_vec = if !defs[0].pinyin.is_empty() {
+            cedict::pinyin_syllables(&defs[0].pinyin)
+        } else if let Some(pinyin_vec) = pinyin_from_definition(&meaning) {
             pinyin_vec
         } else {
             to_pinyin_vec(word, Pinyin::with_tone_num_end)
@@ -188,6 +352,31 @@ pub async fn package_card(
         String::from("")
     };
 
+    let kanji = if config.is_japanese && config.anki.kanji_field.is_some() {
+        kanji_breakdown(dict_db, word)?
+    } else {
+        String::new()
+    };
+
+    let pitch = if config.is_japanese && config.anki.pitch_field.is_some() {
+        pitch_accent(dict_db, &defs[0], config.pitch.display)?
+    } else {
+        String::new()
+    };
+
+    let frequency = defs[0]
+        .frequencies
+        .iter()
+        .map(|freq| {
+            let value = freq
+                .display
+                .clone()
+                .unwrap_or_else(|| freq.value.to_string());
+            format!("{}: {}", escape_html(&freq.list), escape_html(&value))
+        })
+        .collect::<Vec<_>>()
+        .join("<br>");
+
     let ndata = NoteData {
         word: word.to_string(),
         sentence,
@@ -195,6 +384,9 @@ pub async fn package_card(
         image,
         audio,
         word_pinyin,
+        kanji,
+        pitch,
+        frequency,
     };
 
     Ok(Some(ndata))
@@ -209,8 +401,28 @@ pub async fn export_words(dict_db: &DictDb, words_file: &Path) -> Result<()> {
     };
     anki_connect.status().await?;
 
+    let mut word_sentences = read_words_file(words_file)?;
+    if config.duplicate_handler.skip_if_dup {
+        // known words are skipped before fetching anything for them
+        let decks = if config.duplicate_handler.scope == "deck" {
+            vec![config.duplicate_handler.deck.clone()]
+        } else {
+            vec![]
+        };
+        let known = anki_connect
+            .known_words(&decks, &config.anki.word_field)
+            .await?;
+        let count = word_sentences.len();
+        word_sentences.retain(|(word, _)| !known.contains(word.trim()));
+        if word_sentences.len() < count {
+            println!(
+                "Skipping {} words already in Anki",
+                count - word_sentences.len()
+            );
+        }
+    }
+
     println!("Starting to generate card data...");
-    let word_sentences = read_words_file(words_file)?;
     let mut notes = vec![];
 
     let bar = ProgressBar::new(word_sentences.len().try_into().unwrap());
@@ -238,3 +450,69 @@ pub async fn export_words(dict_db: &DictDb, words_file: &Path) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_meanings, kanji_breakdown, pinyin_from_definition, split_sentences};
+    use crate::config::{FrequencyRanking, LookupConfig};
+    use crate::test_utils::test_db;
+
+    #[test]
+    fn it_works() {
+        assert_eq!(
+            pinyin_from_definition("俄罗斯 [éluósi]"),
+            Some(vec![
+                "e2".to_string(),
+                "luo2".to_string(),
+                "si5".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn splits_sentences() {
+        assert_eq!(
+            split_sentences("本を読んだ。面白かった！\n 次は？"),
+            vec!["本を読んだ。", "面白かった！", "次は？"]
+        );
+    }
+
+    #[test]
+    fn groups_glosses_by_sense() {
+        let db = test_db(
+            "INSERT INTO tags (name, category, notes, dict_id) VALUES ('n', 'partOfSpeech', 'noun', 1);
+            INSERT INTO entries (expression, reading, meaning, dict_id, definition_tags, sequence, sense)
+            VALUES ('読み', 'よみ', 'reading', 1, 'n', 7, 1),
+                ('読み', 'よみ', 'pronunciation', 1, 'n', 7, 1),
+                ('読み', 'よみ', 'insight', 1, 'n', 7, 3);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: false,
+            freq_ranking: FrequencyRanking::Min,
+        };
+
+        let defs = db.lookup_word("読み", &lookup_config, true).unwrap();
+        let tag = "<span class=\"tag\" data-category=\"partOfSpeech\" title=\"noun\">n</span>";
+        assert_eq!(
+            format_meanings(&db, &defs).unwrap(),
+            format!(
+                "<ol><li>{0} reading</li><li>pronunciation</li><li>{0} insight</li></ol>",
+                tag
+            )
+        );
+    }
+
+    #[test]
+    fn shows_misc_kanji_stats() {
+        let db = test_db(
+            r#"INSERT INTO tags (name, category, notes, dict_id) VALUES ('strokes', 'misc', 'Stroke count', 1),
+                ('freq', 'frequency', 'Frequency', 1);
+            INSERT INTO kanji (character, onyomi, kunyomi, meanings, stats, dict_id)
+            VALUES ('橋', 'キョウ', 'はし', 'bridge', '{"strokes": "16", "freq": "1000"}', 1);"#,
+        );
+        assert_eq!(
+            kanji_breakdown(&db, "橋だ").unwrap(),
+            r#"<span class="kanji">橋</span> <span class="onyomi">キョウ</span> <span class="kunyomi">はし</span> bridge <span class="stats">(Stroke count: 16)</span>"#
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use anyhow::anyhow;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashSet;
 
 use crate::ace::get_config;
+use crate::yomichan::html_to_text;
+
+// Notes requested per notesInfo call
+const NOTES_INFO_BATCH: usize = 500;
 
 pub struct AnkiConnect {
     pub port: usize,
@@ -21,6 +26,12 @@ pub struct DeckModelInfo {
     pub img_field: String,
     pub audio_field: String,
     pub word_pinyin_field: String,
+    #[serde(default)]
+    pub kanji_field: Option<String>,
+    #[serde(default)]
+    pub pitch_field: Option<String>,
+    #[serde(default)]
+    pub freq_field: Option<String>,
 }
 
 #[derive(Debug, Default)]
@@ -37,12 +48,9 @@ pub struct NoteData {
     pub image: Option<Media>,
     pub audio: Option<Media>,
     pub word_pinyin: String,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct AddResult {
-    result: usize,
-    error: String,
+    pub kanji: String,
+    pub pitch: String,
+    pub frequency: String,
 }
 
 impl AnkiConnect {
@@ -52,7 +60,7 @@ impl AnkiConnect {
             .get(format!("http://{}:{}", self.address, self.port))
             .send()
             .await
-            .with_context(|| format!("Failed to connect to AnkiConnect. Is Anki running?"))?
+            .with_context(|| "Failed to connect to AnkiConnect. Is Anki running?".to_string())?
             .text()
             .await?;
 
@@ -90,6 +98,18 @@ impl AnkiConnect {
             },
         });
 
+        if let Some(kanji_field) = &deck_model_info.kanji_field {
+            res["fields"][kanji_field] = json!(note_data.kanji);
+        }
+
+        if let Some(pitch_field) = &deck_model_info.pitch_field {
+            res["fields"][pitch_field] = json!(note_data.pitch);
+        }
+
+        if let Some(freq_field) = &deck_model_info.freq_field {
+            res["fields"][freq_field] = json!(note_data.frequency);
+        }
+
         if let Some(audio) = &note_data.audio {
             let audio_data = json!({
                 "url": audio.url,
@@ -172,4 +192,95 @@ impl AnkiConnect {
         pb.finish_with_message("Done");
         Ok(res)
     }
+
+    // Sends an action and returns its result, failing on the error AnkiConnect gives back
+    async fn invoke(&self, action: &str, params: Value) -> Result<Value> {
+        let post_data = json!({
+            "action": action,
+            "version": 6,
+            "params": params
+        });
+        let client = reqwest::Client::new();
+        let mut res = client
+            .post(format!("http://{}:{}", self.address, self.port))
+            .json(&post_data)
+            .send()
+            .await
+            .with_context(|| "Failed to connect to AnkiConnect. Is Anki running?".to_string())?
+            .json::<Value>()
+            .await?;
+        if let Some(error) = res["error"].as_str() {
+            bail!("AnkiConnect {} failed: {}", action, error);
+        }
+        Ok(res["result"].take())
+    }
+
+    pub async fn find_notes(&self, query: &str) -> Result<Vec<i64>> {
+        let res = self.invoke("findNotes", json!({ "query": query })).await?;
+        Ok(serde_json::from_value(res)?)
+    }
+
+    pub async fn notes_info(&self, notes: &[i64]) -> Result<Vec<Value>> {
+        let res = self.invoke("notesInfo", json!({ "notes": notes })).await?;
+        Ok(serde_json::from_value(res)?)
+    }
+
+    // The words in the given field of the notes in these decks (and their subdecks),
+    // or in the whole collection when no deck is given
+    pub async fn known_words(&self, decks: &[String], word_field: &str) -> Result<HashSet<String>> {
+        let notes = self.find_notes(&known_words_query(decks, word_field)).await?;
+
+        let mut words = HashSet::new();
+        for batch in notes.chunks(NOTES_INFO_BATCH) {
+            for note in self.notes_info(batch).await? {
+                if let Some(value) = note["fields"][word_field]["value"].as_str() {
+                    let word = html_to_text(value);
+                    if !word.is_empty() {
+                        words.insert(word);
+                    }
+                }
+            }
+        }
+        Ok(words)
+    }
+}
+
+// Searches for the notes with a non empty word field in any of the decks
+fn known_words_query(decks: &[String], word_field: &str) -> String {
+    let query = format!("\"{}:_*\"", word_field);
+    if decks.is_empty() {
+        return query;
+    }
+    let decks = decks
+        .iter()
+        .map(|deck| format!("\"deck:{}\"", escape_search(deck)))
+        .collect::<Vec<_>>();
+    format!("({}) {}", decks.join(" OR "), query)
+}
+
+// Escapes text for a quoted term of an Anki search, where `*` and `_` are wildcards
+fn escape_search(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | '*' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::known_words_query;
+
+    #[test]
+    fn quotes_deck_names() {
+        assert_eq!(known_words_query(&[], "Word"), r#""Word:_*""#);
+        let decks = ["My Deck".to_string(), r#"Say "hi"::Sub_1"#.to_string()];
+        assert_eq!(
+            known_words_query(&decks, "Word"),
+            r#"("deck:My Deck" OR "deck:Say \"hi\"::Sub\_1") "Word:_*""#
+        );
+    }
 }
@@ -0,0 +1,100 @@
+use crate::yomichan::TermEntry;
+
+// `Traditional Simplified [pin1 yin1] /definition 1/definition 2/`
+#[derive(Debug, PartialEq)]
+pub struct CedictEntry {
+    pub traditional: String,
+    pub simplified: String,
+    pub pinyin: String,
+    pub definitions: Vec<String>,
+}
+
+// Comments and malformed lines give None
+pub fn parse_line(line: &str) -> Option<CedictEntry> {
+    if line.starts_with('#') {
+        return None;
+    }
+    let (traditional, rest) = line.trim().split_once(' ')?;
+    let (simplified, rest) = rest.split_once(' ')?;
+    let (pinyin, rest) = rest.strip_prefix('[')?.split_once(']')?;
+    let definitions = rest
+        .trim()
+        .strip_prefix('/')?
+        .split('/')
+        .filter(|definition| !definition.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    if definitions.is_empty() {
+        return None;
+    }
+
+    Some(CedictEntry {
+        traditional: traditional.to_string(),
+        simplified: simplified.to_string(),
+        pinyin: pinyin.to_string(),
+        definitions,
+    })
+}
+
+// Header lines look like `#! date=2024-05-01T05:47:26Z`
+pub fn parse_header(line: &str, key: &str) -> Option<String> {
+    let (name, value) = line.strip_prefix("#!")?.trim().split_once('=')?;
+    (name == key).then(|| value.to_string())
+}
+
+// Numbered pinyin as the syllables used on cards, with ü spelled out and
+// the neutral tone numbered like the rest
+pub fn pinyin_syllables(pinyin: &str) -> Vec<String> {
+    pinyin
+        .split_whitespace()
+        .map(|syllable| {
+            let syllable = syllable.replace("u:", "ü").replace("U:", "Ü");
+            if syllable.ends_with(|c: char| c.is_ascii_digit())
+                || !syllable.chars().all(char::is_alphanumeric)
+            {
+                syllable
+            } else {
+                format!("{}5", syllable)
+            }
+        })
+        .collect()
+}
+
+impl From<CedictEntry> for TermEntry {
+    fn from(entry: CedictEntry) -> Self {
+        TermEntry {
+            expression: entry.simplified.clone(),
+            reading: entry.pinyin.clone(),
+            meanings: entry.definitions,
+            traditional: entry.traditional,
+            simplified: entry.simplified,
+            pinyin: entry.pinyin,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_header, parse_line, pinyin_syllables, CedictEntry};
+
+    #[test]
+    fn parses_cedict_lines() {
+        assert_eq!(
+            parse_line("中國 中国 [Zhong1 guo2] /China/Middle Kingdom/\n"),
+            Some(CedictEntry {
+                traditional: "中國".to_string(),
+                simplified: "中国".to_string(),
+                pinyin: "Zhong1 guo2".to_string(),
+                definitions: vec!["China".to_string(), "Middle Kingdom".to_string()],
+            })
+        );
+        assert_eq!(parse_line("# CC-CEDICT"), None);
+        assert_eq!(parse_line("中國 中国 [Zhong1 guo2]"), None);
+        assert_eq!(
+            parse_header("#! date=2024-05-01T05:47:26Z", "date"),
+            Some("2024-05-01T05:47:26Z".to_string())
+        );
+        assert_eq!(pinyin_syllables("nu:3 er5 men"), vec!["nü3", "er5", "men5"]);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::anki::DeckModelInfo;
+use crate::dict::DbFrequency;
+use crate::pitch::PitchDisplay;
 use anyhow::Context;
 use anyhow::Result;
 use serde_derive::{Deserialize, Serialize};
@@ -15,6 +17,10 @@ pub struct Config {
     pub lookup: LookupConfig,
     pub is_japanese: bool,
     pub duplicate_handler: DuplicateConfig,
+    #[serde(default)]
+    pub pitch: PitchConfig,
+    #[serde(default)]
+    pub deinflect: DeinflectConfig,
 }
 
 #[derive(Serialize, Hash, Deserialize, Debug)]
@@ -41,6 +47,78 @@ pub struct AnkiConnectConfig {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LookupConfig {
     pub sort_freq: bool,
+    #[serde(default)]
+    pub freq_ranking: FrequencyRanking,
+}
+
+// How the ranks of several frequency lists combine into the one used for sorting
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(from = "String", into = "String")]
+pub enum FrequencyRanking {
+    #[default]
+    Min,
+    HarmonicMean,
+    List(String),
+}
+
+impl From<String> for FrequencyRanking {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "min" => FrequencyRanking::Min,
+            "harmonic-mean" => FrequencyRanking::HarmonicMean,
+            _ => FrequencyRanking::List(value),
+        }
+    }
+}
+
+impl From<FrequencyRanking> for String {
+    fn from(ranking: FrequencyRanking) -> Self {
+        match ranking {
+            FrequencyRanking::Min => "min".to_string(),
+            FrequencyRanking::HarmonicMean => "harmonic-mean".to_string(),
+            FrequencyRanking::List(name) => name,
+        }
+    }
+}
+
+impl FrequencyRanking {
+    // Taken by the ways of combining lists, so they can't name a single list
+    pub const RESERVED: &'static [&'static str] = &["min", "harmonic-mean"];
+
+    pub fn rank(&self, frequencies: &[DbFrequency]) -> Option<i64> {
+        match self {
+            FrequencyRanking::Min => frequencies.iter().map(|freq| freq.value).min(),
+            FrequencyRanking::HarmonicMean => {
+                if frequencies.is_empty() {
+                    return None;
+                }
+                let inverse_sum: f64 = frequencies
+                    .iter()
+                    .map(|freq| 1.0 / freq.value.max(1) as f64)
+                    .sum();
+                Some((frequencies.len() as f64 / inverse_sum).round() as i64)
+            }
+            FrequencyRanking::List(name) => frequencies
+                .iter()
+                .find(|freq| &freq.list == name)
+                .map(|freq| freq.value),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PitchConfig {
+    pub display: PitchDisplay,
+}
+
+// Extra deinflection rules in the yomichan deinflect.json format
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DeinflectConfig {
+    #[serde(default)]
+    pub rules_file: Option<String>,
+    // use only the rules file instead of adding it to the built-in rules
+    #[serde(default)]
+    pub replace_rules: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1,11 +1,20 @@
+use anyhow::{bail, Result};
 use serde_derive::Deserialize;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+// One bit per rule type (e.g. "v1", "adj-i") of the loaded rules
+pub type RuleFlags = u64;
 
 pub struct Deinflector {
     normalized_reasons: NormalizedReasons,
+    rule_types: HashMap<String, RuleFlags>,
+    // deinflections of the words seen so far, batches look up many words more than once
+    cache: RefCell<HashMap<String, Rc<Vec<DeinflectResult>>>>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ReasonInfo {
     kana_in: String,
@@ -20,8 +29,8 @@ type Reasons = HashMap<String, Vec<ReasonInfo>>;
 pub struct NormalizedReasonInfo {
     kana_in: String,
     kana_out: String,
-    bits_in: u8,
-    bits_out: u8,
+    bits_in: RuleFlags,
+    bits_out: RuleFlags,
 }
 
 pub type NormalizedReasons = HashMap<String, Vec<NormalizedReasonInfo>>;
@@ -29,29 +38,64 @@ pub type NormalizedReasons = HashMap<String, Vec<NormalizedReasonInfo>>;
 #[derive(Debug, Clone)]
 pub struct DeinflectResult {
     pub term: String,
-    rules: u8,
-    reasons: Vec<String>,
+    rules: RuleFlags,
+    // the deinflections that led to the term, outermost last
+    pub reasons: Vec<String>,
 }
 
 impl Deinflector {
-    pub fn new(deinflect_json: &'static str) -> Self {
-        let mut rule_types: HashMap<&str, u8> = HashMap::new();
-        rule_types.insert("v1", 0b00000001); // Verb ichidan
-        rule_types.insert("v5", 0b00000010); // Verb godan
-        rule_types.insert("vs", 0b00000100); // Verb suru
-        rule_types.insert("vk", 0b00001000); // Verb kuru
-        rule_types.insert("vz", 0b00010000); // Verb zuru
-        rule_types.insert("adj-i", 0b00100000); // Adjective i
-        rule_types.insert("iru", 0b01000000); // Intermediate -iru endings for progressive or perfect tense
-
-        let reasons: Reasons = serde_json::from_str::<Reasons>(deinflect_json).unwrap();
+    pub fn new(deinflect_json: &str) -> Self {
+        Self::from_rules(&[deinflect_json]).unwrap()
+    }
+
+    // Merges rule files in the yomichan deinflect.json format, a reason found in
+    // several of them gets the variants of all of them
+    pub fn from_rules(rule_files: &[&str]) -> Result<Self> {
+        let mut reasons: Reasons = HashMap::new();
+        for rule_file in rule_files {
+            for (reason, variants) in serde_json::from_str::<Reasons>(rule_file)? {
+                let known = reasons.entry(reason).or_default();
+                for variant in variants {
+                    if !known.contains(&variant) {
+                        known.push(variant);
+                    }
+                }
+            }
+        }
+
+        let rule_types = Self::rule_types(&reasons)?;
         let normalized_reasons: NormalizedReasons = Self::normalize_reasons(reasons, &rule_types);
-        Self { normalized_reasons }
+        Ok(Self {
+            normalized_reasons,
+            rule_types,
+            cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // Gives every rule name used by the reasons its own bit, in alphabetical order
+    pub fn rule_types(reasons: &Reasons) -> Result<HashMap<String, RuleFlags>> {
+        let names = reasons
+            .values()
+            .flatten()
+            .flat_map(|info| info.rules_in.iter().chain(&info.rules_out))
+            .collect::<BTreeSet<_>>();
+        if names.len() > RuleFlags::BITS as usize {
+            bail!(
+                "Deinflection rules use {} rule types, at most {} are supported",
+                names.len(),
+                RuleFlags::BITS
+            );
+        }
+        Ok(names
+            .into_iter()
+            .enumerate()
+            .map(|(bit, name)| (name.clone(), 1 << bit))
+            .collect())
     }
 
     pub fn normalize_reasons(
         reasons: Reasons,
-        rule_types: &HashMap<&'static str, u8>,
+        rule_types: &HashMap<String, RuleFlags>,
     ) -> NormalizedReasons {
         let mut normalized_reason: NormalizedReasons = HashMap::new();
 
@@ -64,8 +108,8 @@ impl Deinflector {
                 rules_out,
             } in reason_info
             {
-                let bits_in = Self::rule_to_rule_flags(rules_in.to_vec(), &rule_types);
-                let bits_out = Self::rule_to_rule_flags(rules_out.to_vec(), &rule_types);
+                let bits_in = Self::rule_to_rule_flags(rules_in.to_vec(), rule_types);
+                let bits_out = Self::rule_to_rule_flags(rules_out.to_vec(), rule_types);
 
                 variants.push(NormalizedReasonInfo {
                     kana_in: kana_in.to_string(),
@@ -80,7 +124,16 @@ impl Deinflector {
         normalized_reason
     }
 
-    pub fn deinflect(&self, word: String) -> Vec<DeinflectResult> {
+    pub fn deinflect(&self, word: String) -> Rc<Vec<DeinflectResult>> {
+        if let Some(results) = self.cache.borrow().get(&word) {
+            return Rc::clone(results);
+        }
+        let results = Rc::new(self.deinflect_uncached(word.clone()));
+        self.cache.borrow_mut().insert(word, Rc::clone(&results));
+        results
+    }
+
+    fn deinflect_uncached(&self, word: String) -> Vec<DeinflectResult> {
         let mut results: Vec<DeinflectResult> = vec![DeinflectResult {
             term: word,
             rules: 0,
@@ -98,7 +151,7 @@ impl Deinflector {
                 {
                     if (curr.rules != 0 && (curr.rules & *bits_in) == 0)
                         || !curr.term.ends_with(kana_in)
-                        || (curr.term.len() - kana_in.len() + kana_out.len()) <= 0
+                        || (curr.term.len() - kana_in.len() + kana_out.len()) == 0
                     {
                         continue;
                     }
@@ -106,7 +159,7 @@ impl Deinflector {
                     let mut rsns = vec![reason.clone()];
                     rsns.extend_from_slice(curr.reasons.as_slice());
                     let new_res = DeinflectResult {
-                        term: curr.term[0..curr.term.len() - kana_in.len()].to_string() + &kana_out,
+                        term: curr.term[0..curr.term.len() - kana_in.len()].to_string() + kana_out,
                         rules: *bits_out,
                         reasons: rsns,
                     };
@@ -118,10 +171,22 @@ impl Deinflector {
         results
     }
 
-    pub fn rule_to_rule_flags(rules: Vec<String>, rule_types: &HashMap<&'static str, u8>) -> u8 {
+    // Converts the space separated rule identifiers of a dictionary entry (e.g. "v5 vt")
+    pub fn entry_rule_flags(&self, rule_identifiers: &str) -> RuleFlags {
+        let rules = rule_identifiers
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        Self::rule_to_rule_flags(rules, &self.rule_types)
+    }
+
+    pub fn rule_to_rule_flags(
+        rules: Vec<String>,
+        rule_types: &HashMap<String, RuleFlags>,
+    ) -> RuleFlags {
         let mut value = 0;
         for rule in rules {
-            let rule_bits = rule_types.get(rule.as_str());
+            let rule_bits = rule_types.get(&rule);
             if let Some(rule_bits) = rule_bits {
                 value |= rule_bits;
             }
@@ -129,3 +194,57 @@ impl Deinflector {
         value
     }
 }
+
+impl DeinflectResult {
+    // The original word carries no rules and may be any kind of term, otherwise the
+    // entry has to be of the part of speech the deinflection produced. Like yomichan,
+    // entries without known rules (CEDICT, StarDict, v1 banks, rows imported before
+    // rules were stored) could be any part of speech and are let through
+    pub fn matches_rules(&self, entry_rules: RuleFlags) -> bool {
+        self.rules == 0 || entry_rules == 0 || (self.rules & entry_rules) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deinflector;
+
+    #[test]
+    fn validates_rules() {
+        let deinflector = Deinflector::new(include_str!("../data/deinflect.json"));
+        let results = deinflector.deinflect("食べた".to_string());
+        let taberu = results
+            .iter()
+            .find(|result| result.term == "食べる")
+            .unwrap();
+
+        assert!(taberu.matches_rules(deinflector.entry_rule_flags("v1 vt")));
+        assert!(!taberu.matches_rules(deinflector.entry_rule_flags("v5")));
+        assert!(results[0].matches_rules(deinflector.entry_rule_flags("v5")));
+
+        // repeated words are served from the cache
+        let again = deinflector.deinflect("食べた".to_string());
+        assert!(std::rc::Rc::ptr_eq(&results, &again));
+    }
+
+    #[test]
+    fn loads_extra_rules() {
+        let extra = r#"{
+            "-chau": [{"kanaIn": "ちゃう", "kanaOut": "る", "rulesIn": ["v5"], "rulesOut": ["v1"]}],
+            "-ssu": [{"kanaIn": "っす", "kanaOut": "です", "rulesIn": [], "rulesOut": ["copula"]}]
+        }"#;
+        let deinflector =
+            Deinflector::from_rules(&[include_str!("../data/deinflect.json"), extra]).unwrap();
+        let results = deinflector.deinflect("食べちゃう".to_string());
+        let taberu = results
+            .iter()
+            .find(|result| result.term == "食べる")
+            .unwrap();
+        assert_eq!(taberu.reasons, vec!["-chau"]);
+        assert!(taberu.matches_rules(deinflector.entry_rule_flags("v1")));
+
+        // rule names are given bits as they come
+        assert_ne!(deinflector.entry_rule_flags("copula"), 0);
+        assert!(Deinflector::from_rules(&["not json"]).is_err());
+    }
+}
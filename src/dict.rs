@@ -1,23 +1,35 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use directories::BaseDirs;
-use indicatif::{ProgressBar, ProgressStyle};
-use rusqlite::{params, Connection, Transaction};
-use serde_derive::Deserialize;
-use std::convert::TryInto;
-use std::{fs, path::Path};
+use flate2::read::MultiGzDecoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use once_cell::unsync::OnceCell;
+use rusqlite::{params, Connection, OptionalExtension, ToSql, Transaction};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+use std::sync::{mpsc, Mutex};
+use std::{fs, iter, mem, path::Path, slice, thread};
 
 use crate::ace::get_config;
+use crate::cedict;
+use crate::config::{DeinflectConfig, FrequencyRanking, LookupConfig};
 use crate::deinflect;
+use crate::jmdict::JmdictReader;
+use crate::migrations;
+use crate::stardict::StarDict;
+use crate::yomichan::{
+    compare_revisions, parse_frequency, BankWriter, stream_bank, PitchData, TermEntry, YomichanDict,
+    YomichanEntryV1, YomichanEntryV3, YomichanKanji, YomichanMetaEntry, YomichanSource,
+    YomichanTag, YomichanWriter,
+};
 
 #[derive(Debug)]
 pub struct DictConn {
     pub conn: Connection,
-    pub new: bool,
-}
-
-#[derive(Debug, Deserialize)]
-struct YomichanDict {
-    title: String,
 }
 
 #[derive(Debug)]
@@ -27,38 +39,220 @@ pub struct DbDictionary {
     pub priority: i64,
     pub fallback: bool,
     pub enabled: bool,
+    pub revision: String,
+    pub author: String,
+    pub url: String,
+    pub description: String,
+    pub sequenced: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DbDictEntry {
     pub id: i64,
     pub expression: String,
     pub reading: String,
     pub meaning: String,
     pub dict_id: i64,
+    pub definition_tags: String,
+    pub term_tags: String,
+    pub rules: String,
+    pub popularity: f64,
+    pub sequence: Option<i64>,
+    // shared by the glosses of one sense, unset for rows imported before it existed
+    pub sense: Option<i64>,
+    pub priority: i64,
+    pub traditional: String,
+    pub simplified: String,
+    pub pinyin: String,
+    pub dict: String,
+    // one value per enabled frequency list that knows the word
+    pub frequencies: Vec<DbFrequency>,
+    pub rank: Option<i64>,
+}
+
+// The entries found for one form of a looked up word
+#[derive(Debug, Serialize)]
+pub struct LookupResult {
+    pub term: String,
+    // deinflections from the word to the term, e.g. ["causative", "past"]
+    pub reasons: Vec<String>,
+    pub entries: Vec<DbDictEntry>,
+}
+
+// All entries of one term, the reading is filled in for kana terms stored without one
+#[derive(Debug)]
+pub struct DbTermGroup {
+    pub expression: String,
+    pub reading: String,
+    pub entries: Vec<DbDictEntry>,
+}
+
+#[derive(Debug)]
+pub struct DbFrequencyList {
+    pub name: String,
+    pub enabled: bool,
+    pub words: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DbFrequency {
+    pub list: String,
+    pub value: i64,
+    pub display: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct DbKanji {
+    pub character: String,
+    pub onyomi: String,
+    pub kunyomi: String,
+    pub meanings: Vec<String>,
+    pub stats: serde_json::Map<String, serde_json::Value>,
+    pub dict_id: i64,
 }
 
-#[derive(Debug, Deserialize)]
-struct YomichanEntryV1 {
-    expression: String,
-    reading: String,
-    definition_tags: String,
-    rule_identifiers: String,
-    popularity: i32,
-    meanings: Vec<String>,
-    sequence: usize,
-    term_tags: String,
+#[derive(Debug, Clone)]
+pub struct DbTag {
+    pub name: String,
+    pub category: String,
+    pub notes: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct YomichanFrequencyEntry {
     pub expression: String,
-    pub tag: String,
+    // empty when the frequency applies to every reading
+    pub reading: String,
     pub frequency: i64,
+    pub display: Option<String>,
 }
 
 pub struct DictDb {
     conn: DictConn,
+    // built on the first japanese lookup
+    deinflector: OnceCell<deinflect::Deinflector>,
+}
+
+// Term bank rows are collected into batches of this many entries before being written
+const ENTRY_BATCH_SIZE: usize = 1000;
+// Parsed batches a bank can have waiting for the writer before its worker blocks
+const BANK_QUEUE_BATCHES: usize = 4;
+// Rows per INSERT, at 14 parameters each this stays well below SQLite's variable limit
+const INSERT_BATCH_ROWS: usize = 100;
+
+// Words per frequency query, one parameter each
+const FREQUENCY_BATCH_WORDS: usize = 500;
+// Words per query for the ones a dictionary knows, up to two parameters each
+const KNOWN_BATCH_WORDS: usize = 500;
+
+// Columns read into a DbDictEntry by entry_from_row
+const ENTRY_COLUMNS: &str = "entries.id, entries.expression, entries.reading, entries.meaning,
+    entries.dict_id, entries.definition_tags, entries.term_tags, entries.rules, entries.popularity,
+    entries.sequence, dicts.priority, entries.traditional, entries.simplified, entries.pinyin,
+    dicts.title, entries.sense";
+
+const ENTRIES_FROM: &str = "entries INNER JOIN dicts ON entries.dict_id = dicts.id";
+// Goes through the few dictionaries first, so that a term is found with one dict_word_idx
+// search per dictionary
+const ENTRIES_BY_DICT_FROM: &str = "dicts CROSS JOIN entries ON entries.dict_id = dicts.id";
+
+fn entries_query(from: &str, condition: &str) -> String {
+    format!(
+        "SELECT {} FROM {}
+        WHERE enabled = 1 AND fallback = :fallback AND {}
+        ORDER BY priority DESC, entries.id ASC",
+        ENTRY_COLUMNS, from, condition
+    )
+}
+
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<DbDictEntry> {
+    Ok(DbDictEntry {
+        id: row.get(0)?,
+        expression: row.get(1)?,
+        reading: row.get(2)?,
+        meaning: row.get(3)?,
+        dict_id: row.get(4)?,
+        definition_tags: row.get(5)?,
+        term_tags: row.get(6)?,
+        rules: row.get(7)?,
+        popularity: row.get(8)?,
+        sequence: row.get(9)?,
+        sense: row.get(15)?,
+        priority: row.get(10)?,
+        traditional: row.get(11)?,
+        simplified: row.get(12)?,
+        pinyin: row.get(13)?,
+        dict: row.get(14)?,
+        frequencies: vec![],
+        rank: None,
+    })
+}
+
+// List names are also what `freq_ranking` picks a single list by
+fn check_frequency_list_name(name: &str) -> Result<()> {
+    if FrequencyRanking::RESERVED.contains(&name) {
+        bail!("A frequency list can't be named {}, freq_ranking uses it", name);
+    }
+    Ok(())
+}
+
+// The frequency in a row of a meta bank, which can mix frequency and pitch data
+fn frequency_entry(entry: YomichanMetaEntry) -> Option<YomichanFrequencyEntry> {
+    if entry.mode != "freq" {
+        return None;
+    }
+    let data = parse_frequency(&entry.data)?;
+    let reading = data
+        .reading
+        .filter(|reading| *reading != entry.expression)
+        .unwrap_or_default();
+    Some(YomichanFrequencyEntry {
+        expression: entry.expression,
+        reading,
+        frequency: data.value,
+        display: data.display,
+    })
+}
+
+// Whether two term bank rows only differ in their meanings
+fn same_term(a: &Value, b: &Value) -> bool {
+    [0, 1, 2, 3, 4, 6, 7].iter().all(|&i| a[i] == b[i])
+}
+
+// CC-CEDICT terms are stored under their simplified form, the traditional one gets a
+// copy of the row so both can be looked up in yomichan
+fn push_term(
+    banks: &mut BankWriter,
+    (term, _, traditional): (Value, Option<i64>, String),
+) -> Result<()> {
+    if !traditional.is_empty() && term[0] != traditional {
+        let mut copy = term.clone();
+        copy[0] = json!(traditional);
+        banks.push(term)?;
+        banks.push(copy)
+    } else {
+        banks.push(term)
+    }
+}
+
+// Opens a plain or gzipped text file, with a progress bar over the bytes read from disk
+fn open_text(path: &Path) -> Result<(Box<dyn BufRead>, ProgressBar)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let bar = ProgressBar::new(file.metadata()?.len()).with_message("entries");
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template(
+                "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes:>7}/{total_bytes:7}",
+            )
+            .progress_chars("#>-"),
+    );
+    let file = BufReader::new(bar.wrap_read(file));
+    let reader: Box<dyn BufRead> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else {
+        Box::new(file)
+    };
+    Ok((reader, bar))
 }
 
 fn all_kana(word: &str) -> bool {
@@ -73,115 +267,529 @@ fn all_kana(word: &str) -> bool {
 
 impl DictDb {
     pub fn new() -> Result<Self> {
-        let conn = DictConn::new()?;
-        if conn.new {
-            let res = conn.setup_schema();
-            if res.is_err() {
-                eprintln!("{}", res.unwrap_err());
-                bail!("Unable to setup database schema")
-            }
+        Self::open(DictConn::new()?.conn)
+    }
+
+    pub fn open(mut conn: Connection) -> Result<Self> {
+        if let Err(err) = migrations::migrate(&mut conn) {
+            eprintln!("{}", err);
+            bail!("Unable to upgrade database schema")
         }
-        Ok(DictDb { conn })
+        Ok(DictDb {
+            conn: DictConn { conn },
+            deinflector: OnceCell::new(),
+        })
+    }
+
+    // The rules are read on first use, later calls share the same deinflector
+    pub fn deinflector(&self, config: &DeinflectConfig) -> Result<&deinflect::Deinflector> {
+        self.deinflector.get_or_try_init(|| {
+            let builtin = include_str!("../data/deinflect.json");
+            let path = match &config.rules_file {
+                Some(path) => path,
+                None => return Ok(deinflect::Deinflector::new(builtin)),
+            };
+            let rules = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read deinflection rules from {}", path))?;
+            let rule_files = if config.replace_rules {
+                vec![rules.as_str()]
+            } else {
+                vec![builtin, rules.as_str()]
+            };
+            deinflect::Deinflector::from_rules(&rule_files)
+                .with_context(|| format!("Invalid deinflection rules in {}", path))
+        })
     }
 
-    pub fn load_yomichan_dict(&mut self, path: &Path, title: String) -> Result<()> {
-        if Self::validate_yomichan(path, false) {
-            // setup transaction for faster writes
-            let tx = self.conn.get_transaction()?;
+    pub fn load_yomichan_dict(
+        &mut self,
+        path: &Path,
+        title: String,
+        replace: bool,
+        defer_indexes: bool,
+    ) -> Result<()> {
+        let mut source = YomichanSource::open(path)?;
+        Self::validate_yomichan(&source, path, false)?;
+        let index = source.read_index()?;
+        let format = index.format();
+        if !(1..=3).contains(&format) {
+            bail!("Unsupported dictionary format version {}", format);
+        }
 
-            if Self::get_dict_id(&title, &tx).is_ok() {
-                return Ok(());
+        self.import_dict(&title, &index, replace, defer_indexes, |dict_id, tx| {
+            // version 1 dictionaries keep their tags in the index
+            for tag in index.tags() {
+                Self::insert_tag(tag, dict_id, tx)?;
+            }
+            for tag_bank in source.banks("tag_bank_")? {
+                stream_bank(source.read(&tag_bank)?, |tag| {
+                    Ok(Self::insert_tag(tag, dict_id, tx)?)
+                })?;
             }
 
-            let paths = fs::read_dir(path)?
-                .filter(|path| {
-                    let filename = path.as_ref().unwrap().file_name();
-                    let filename = filename.to_str().unwrap();
-                    filename.starts_with("term_bank_") && filename.ends_with(".json")
-                })
-                .collect::<Result<Vec<_>, std::io::Error>>()?;
-
-            let total = paths.len();
-
-            let dict_id = Self::insert_dict(&title, &tx)?;
-            for (index, term_bank) in paths.iter().enumerate() {
-                let text = std::fs::read_to_string(&term_bank.path())?;
-                let data: Vec<YomichanEntryV1> = serde_json::from_str(&text)?;
-                let msg = format!("{}/{}", index + 1, total);
-                let bar = ProgressBar::new(data.len().try_into().unwrap()).with_message(msg);
-                bar.set_style(
-                    ProgressStyle::default_bar()
-                        .template(
-                            "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>7}/{len:7}",
-                        )
-                        .progress_chars("#>-"),
+            let banks = source.banks("term_bank_")?;
+            Self::import_term_banks(&mut source, path, &banks, format, dict_id, tx)?;
+
+            // frequencies are kept as a list named after the dictionary
+            let mode = index.frequency_mode.as_deref().unwrap_or("rank-based");
+            let mut list_id = None;
+            let mut skipped_pitches = 0;
+            for meta_bank in source.banks("term_meta_bank_")? {
+                stream_bank(source.read(&meta_bank)?, |entry: YomichanMetaEntry| {
+                    if entry.mode == "pitch" {
+                        let pitch: PitchData = serde_json::from_value(entry.data)?;
+                        skipped_pitches +=
+                            Self::insert_pitch(&entry.expression, pitch, dict_id, tx)?;
+                    } else if let Some(mut entry) = frequency_entry(entry) {
+                        let list_id = match list_id {
+                            Some(list_id) => list_id,
+                            None => {
+                                check_frequency_list_name(&title)?;
+                                if Self::get_frequency_list_id(&title, tx).is_ok() {
+                                    bail!("A frequency list named {} already exists", title);
+                                }
+                                *list_id.insert(Self::insert_frequency_list(
+                                    &title,
+                                    mode,
+                                    Some(dict_id),
+                                    tx,
+                                )?)
+                            }
+                        };
+                        Self::update_frequency_entry(&mut entry, list_id, tx)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            if skipped_pitches > 0 {
+                eprintln!(
+                    "Skipped {} pitch accents without a single downstep position",
+                    skipped_pitches
                 );
+            }
 
-                for entry in data {
-                    Self::insert_entry(entry, dict_id, &tx)?;
-                    bar.inc(1);
-                }
+            for kanji_bank in source.banks("kanji_bank_")? {
+                stream_bank(source.read(&kanji_bank)?, |kanji| {
+                    Ok(Self::insert_kanji(kanji, dict_id, tx)?)
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn load_jmdict(
+        &mut self,
+        path: &Path,
+        title: String,
+        replace: bool,
+        defer_indexes: bool,
+    ) -> Result<()> {
+        let (reader, bar) = open_text(path)?;
+        let mut jmdict = JmdictReader::new(reader)?;
+        let index = YomichanDict {
+            revision: jmdict.created.clone(),
+            author: "Electronic Dictionary Research and Development Group".to_string(),
+            url: "https://www.edrdg.org/jmdict/j_jmdict.html".to_string(),
+            description: "JMdict".to_string(),
+            sequenced: true,
+            ..Default::default()
+        };
 
-                bar.finish_and_clear();
+        self.import_dict(&title, &index, replace, defer_indexes, |dict_id, tx| {
+            let entries = iter::from_fn(|| jmdict.next_entry().transpose());
+            let terms = entries.flat_map(|entry| match entry {
+                Ok(entry) => entry.terms().into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(err)],
+            });
+            Self::insert_terms(terms, bar, dict_id, tx)?;
+
+            // the entity definitions describe the tags they stand for
+            for (name, notes) in &jmdict.entities {
+                let element = jmdict
+                    .categories
+                    .iter()
+                    .find(|(tag, _)| tag == name)
+                    .map(|(_, element)| element.as_str());
+                let category = match element {
+                    Some("pos") => "partOfSpeech",
+                    Some("dial") => "dialect",
+                    Some("ke_inf") | Some("re_inf") => "expression",
+                    Some(element) => element,
+                    None => "",
+                };
+                let tag = YomichanTag {
+                    name: name.clone(),
+                    category: category.to_string(),
+                    order: 0,
+                    notes: notes.clone(),
+                    score: 0,
+                };
+                Self::insert_tag(tag, dict_id, tx)?;
             }
+            Ok(())
+        })
+    }
 
-            if tx.commit().is_err() {
-                bail!("Unable to commit transaction");
+    pub fn load_cedict(
+        &mut self,
+        path: &Path,
+        title: String,
+        replace: bool,
+        defer_indexes: bool,
+    ) -> Result<()> {
+        let (reader, bar) = open_text(path)?;
+        let mut lines = reader.lines().peekable();
+
+        // the header comes first and carries the release date
+        let mut revision = String::new();
+        while let Some(Ok(line)) = lines.peek() {
+            if !line.starts_with('#') {
+                break;
+            }
+            if let Some(date) = cedict::parse_header(line, "date") {
+                revision = date;
             }
+            lines.next();
+        }
+        let index = YomichanDict {
+            revision,
+            author: "MDBG".to_string(),
+            url: "https://www.mdbg.net/chinese/dictionary?page=cc-cedict".to_string(),
+            description: "CC-CEDICT".to_string(),
+            ..Default::default()
+        };
+
+        self.import_dict(&title, &index, replace, defer_indexes, |dict_id, tx| {
+            let terms = lines.filter_map(|line| match line {
+                Ok(line) => cedict::parse_line(&line).map(|entry| Ok(entry.into())),
+                Err(err) => Some(Err(err.into())),
+            });
+            Self::insert_terms(terms, bar, dict_id, tx)
+        })
+    }
 
-            println!("Finished importing dictionary.");
+    pub fn load_stardict(
+        &mut self,
+        path: &Path,
+        title: String,
+        replace: bool,
+        defer_indexes: bool,
+    ) -> Result<()> {
+        let stardict = StarDict::open(path)?;
+        let info = &stardict.info;
+        let index = YomichanDict {
+            revision: if info.date.is_empty() {
+                info.version.clone()
+            } else {
+                info.date.clone()
+            },
+            author: info.author.clone(),
+            url: info.website.clone(),
+            description: info.description.clone(),
+            ..Default::default()
+        };
+
+        self.import_dict(&title, &index, replace, defer_indexes, |dict_id, tx| {
+            let total = stardict.entries.len() + stardict.synonyms.len();
+            let bar = ProgressBar::new(total as u64).with_message("entries");
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>7}/{len:7}",
+                    )
+                    .progress_chars("#>-"),
+            );
+            let terms = stardict.terms()?.inspect(|_| bar.inc(1));
+            Self::insert_terms(terms, bar.clone(), dict_id, tx)
+        })
+    }
+
+    // What importing any format comes down to: the dictionary is added in one transaction,
+    // replacing an earlier import when asked to, and `write` fills in its contents
+    fn import_dict(
+        &mut self,
+        title: &str,
+        index: &YomichanDict,
+        replace: bool,
+        defer_indexes: bool,
+        write: impl FnOnce(i64, &Transaction) -> Result<()>,
+    ) -> Result<()> {
+        // setup transaction for faster writes
+        let tx = self.conn.get_transaction()?;
+        let dict_id = match Self::create_dict(title, index, replace, &tx)? {
+            Some(dict_id) => dict_id,
+            None => return Ok(()),
+        };
+
+        if defer_indexes {
+            migrations::drop_entry_indexes(&tx)?;
         }
+
+        write(dict_id, &tx)?;
+
+        if defer_indexes {
+            println!("Building indexes...");
+            migrations::create_entry_indexes(&tx)?;
+        }
+
+        if tx.commit().is_err() {
+            bail!("Unable to commit transaction");
+        }
+
+        println!("Finished importing dictionary.");
         Ok(())
     }
 
-    pub fn update_frequency(&mut self, path: &Path, avg: bool, corpus: bool) -> Result<()> {
-        if Self::validate_yomichan(path, true) {
-            // setup transaction for faster writes
-            let tx = self.conn.get_transaction()?;
-
-            let mut paths = fs::read_dir(path)?
-                .filter(|path| {
-                    let filename = path.as_ref().unwrap().file_name();
-                    let filename = filename.to_str().unwrap();
-                    filename.starts_with("term_meta_bank_") && filename.ends_with(".json")
-                })
-                .collect::<Result<Vec<_>, std::io::Error>>()?;
-
-            paths.sort_by_key(|a| a.file_name());
-
-            let total = paths.len();
-            let mut rank = 1;
-            for (index, term_bank) in paths.iter().enumerate() {
-                let text = std::fs::read_to_string(&term_bank.path())?;
-                let mut data: Vec<YomichanFrequencyEntry> = serde_json::from_str(&text)?;
-                let msg = format!("{}/{}", index + 1, total);
-                let bar = ProgressBar::new(data.len().try_into().unwrap()).with_message(msg);
-                bar.set_style(
-                    ProgressStyle::default_bar()
-                        .template(
-                            "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>7}/{len:7}",
-                        )
-                        .progress_chars("#>-"),
-                );
+    // Writes the terms of a format's parser in batches as they come in
+    fn insert_terms(
+        terms: impl Iterator<Item = Result<TermEntry>>,
+        bar: ProgressBar,
+        dict_id: i64,
+        tx: &Transaction,
+    ) -> Result<()> {
+        let mut next_sense = Self::first_sense(tx)?;
+        let mut batch: Vec<TermEntry> = Vec::with_capacity(ENTRY_BATCH_SIZE);
+        for term in terms {
+            batch.push(term?);
+            if batch.len() == ENTRY_BATCH_SIZE {
+                Self::insert_entries(&batch, dict_id, &mut next_sense, tx)?;
+                batch.clear();
+            }
+        }
+        Self::insert_entries(&batch, dict_id, &mut next_sense, tx)?;
+        bar.finish_and_clear();
+        Ok(())
+    }
 
-                for entry in data.iter_mut() {
-                    if corpus {
-                        entry.frequency = rank;
-                    }
-                    Self::update_frequency_entry(entry, avg, &tx)?;
-                    bar.inc(1);
-                    rank += 1;
+    // Adds the dictionary about to be imported, or None when it is already there and not replaced
+    fn create_dict(
+        title: &str,
+        index: &YomichanDict,
+        replace: bool,
+        tx: &Transaction,
+    ) -> rusqlite::Result<Option<i64>> {
+        Ok(match Self::get_dict_id(title, tx) {
+            Ok(old_id) if replace => {
+                // the new version keeps the settings of the one it replaces
+                let dict_id = Self::insert_dict(title, index, tx)?;
+                tx.execute(
+                    "UPDATE dicts SET (priority, fallback, enabled) =
+                    (SELECT priority, fallback, enabled FROM dicts WHERE id = ?1) WHERE id = ?2",
+                    params![old_id, dict_id],
+                )?;
+                Self::delete_dict_data(old_id, tx)?;
+                Some(dict_id)
+            }
+            Ok(old_id) => {
+                let installed = Self::get_revision(old_id, tx)?;
+                match compare_revisions(&index.revision, &installed) {
+                    Ordering::Greater => println!(
+                        "A newer revision of {} is available ({} -> {}), pass --replace to upgrade.",
+                        title, installed, index.revision
+                    ),
+                    Ordering::Less => println!(
+                        "{} is already imported with a newer revision ({}), pass --replace to downgrade to {}.",
+                        title, installed, index.revision
+                    ),
+                    Ordering::Equal => println!(
+                        "{} is already imported, pass --replace to import it again.",
+                        title
+                    ),
                 }
-
-                bar.finish_and_clear();
+                None
             }
+            Err(_) => Some(Self::insert_dict(title, index, tx)?),
+        })
+    }
 
-            if tx.commit().is_err() {
-                bail!("Unable to commit transaction");
+    // Term banks are parsed by a pool of workers, each with its own handle on the dictionary,
+    // while this thread writes the parsed entries into the transaction
+    fn import_term_banks(
+        source: &mut YomichanSource,
+        path: &Path,
+        banks: &[String],
+        format: u8,
+        dict_id: i64,
+        tx: &Transaction,
+    ) -> Result<()> {
+        let mut total = 0;
+        for term_bank in banks {
+            total += source.size(term_bank)?;
+        }
+        let style = ProgressStyle::default_bar()
+            .template(
+                "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes:>7}/{total_bytes:7}",
+            )
+            .progress_chars("#>-");
+        let progress = MultiProgress::new();
+        let total_bar = progress.add(ProgressBar::new(total).with_message("terms"));
+        total_bar.set_style(style.clone());
+
+        let workers = thread::available_parallelism()
+            .map(|workers| workers.get())
+            .unwrap_or(1)
+            .min(banks.len());
+        let worker_bars = (0..workers)
+            .map(|_| {
+                let bar = progress.add(ProgressBar::new(0));
+                bar.set_style(style.clone());
+                bar
+            })
+            .collect::<Vec<_>>();
+
+        let next_bank = AtomicUsize::new(0);
+        // each bank gets its own channel of parsed batches, taken by the worker that parses
+        // it and closed once it is done. They are bounded so that workers running ahead of
+        // the writer wait instead of piling up entries in memory
+        let (senders, receivers): (Vec<_>, Vec<_>) = banks
+            .iter()
+            .map(|_| {
+                let (sender, receiver) =
+                    mpsc::sync_channel::<Result<Vec<TermEntry>>>(BANK_QUEUE_BATCHES);
+                (Mutex::new(Some(sender)), receiver)
+            })
+            .unzip();
+
+        thread::scope(|scope| {
+            scope.spawn(|| progress.join_and_clear());
+
+            for bar in worker_bars {
+                let total_bar = total_bar.clone();
+                let next_bank = &next_bank;
+                let senders = &senders;
+                scope.spawn(move || {
+                    let mut source = None;
+                    loop {
+                        let index = next_bank.fetch_add(1, Relaxed);
+                        let term_bank = match banks.get(index) {
+                            Some(term_bank) => term_bank,
+                            None => break,
+                        };
+                        let sender = match senders[index].lock().unwrap().take() {
+                            Some(sender) => sender,
+                            None => break,
+                        };
+                        let res = (|| -> Result<()> {
+                            let source = match source.as_mut() {
+                                Some(source) => source,
+                                None => source.insert(YomichanSource::open(path)?),
+                            };
+                            bar.set_message(term_bank.clone());
+                            bar.set_length(source.size(term_bank)?);
+                            bar.set_position(0);
+                            // buffered on top so the bars are updated per chunk rather than per byte
+                            let reader = BufReader::new(
+                                total_bar.wrap_read(bar.wrap_read(source.read(term_bank)?)),
+                            );
+
+                            let mut batch: Vec<TermEntry> = Vec::with_capacity(ENTRY_BATCH_SIZE);
+                            let mut parsed = |entry: TermEntry| {
+                                batch.push(entry);
+                                if batch.len() == ENTRY_BATCH_SIZE {
+                                    let batch = mem::replace(
+                                        &mut batch,
+                                        Vec::with_capacity(ENTRY_BATCH_SIZE),
+                                    );
+                                    sender
+                                        .send(Ok(batch))
+                                        .map_err(|_| anyhow!("Import was aborted"))?;
+                                }
+                                Ok(())
+                            };
+                            if format == 1 {
+                                stream_bank(reader, |entry: YomichanEntryV1| parsed(entry.into()))
+                            } else {
+                                stream_bank(reader, |entry: YomichanEntryV3| parsed(entry.into()))
+                            }
+                            .with_context(|| format!("Failed to parse {}", term_bank))?;
+                            sender
+                                .send(Ok(batch))
+                                .map_err(|_| anyhow!("Import was aborted"))
+                        })();
+                        if let Err(err) = res {
+                            // nobody is listening anymore when the writer already failed
+                            let _ = sender.send(Err(err));
+                            break;
+                        }
+                    }
+                    bar.finish_and_clear();
+                });
             }
 
-            println!("Finished updating frequency data.");
+            // banks finish out of order, entries are still written in bank order
+            // so that their ids follow the order of the dictionary
+            let res = (|| -> Result<()> {
+                let mut next_sense = Self::first_sense(tx)?;
+                for receiver in &receivers {
+                    for batch in receiver {
+                        Self::insert_entries(&batch?, dict_id, &mut next_sense, tx)?;
+                    }
+                }
+                Ok(())
+            })();
+            // wakes up the workers waiting on a full channel when the writer failed
+            drop(receivers);
+            total_bar.finish_and_clear();
+            res
+        })
+    }
+
+    pub fn update_frequency(&mut self, path: &Path, name: &str, corpus: bool) -> Result<()> {
+        check_frequency_list_name(name)?;
+        let mut source = YomichanSource::open(path)?;
+        Self::validate_yomichan(&source, path, true)?;
+        // setup transaction for faster writes
+        let tx = self.conn.get_transaction()?;
+
+        if Self::get_frequency_list_id(name, &tx).is_ok() {
+            bail!("A frequency list named {} already exists", name);
+        }
+        // ranks are what is stored for corpus lists
+        let mode = match source.read_index()?.frequency_mode {
+            Some(mode) if !corpus => mode,
+            _ => "rank-based".to_string(),
+        };
+        let list_id = Self::insert_frequency_list(name, &mode, None, &tx)?;
+
+        let banks = source.banks("term_meta_bank_")?;
+        let mut total = 0;
+        for term_bank in &banks {
+            total += source.size(term_bank)?;
+        }
+        let bar = ProgressBar::new(total).with_message("frequencies");
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "{msg} {spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes:>7}/{total_bytes:7}",
+                )
+                .progress_chars("#>-"),
+        );
+
+        let mut rank = 1;
+        for term_bank in &banks {
+            // buffered on top so the bar is updated per chunk rather than per byte
+            let reader = BufReader::new(bar.wrap_read(source.read(term_bank)?));
+            stream_bank(reader, |entry: YomichanMetaEntry| {
+                let mut entry = match frequency_entry(entry) {
+                    Some(entry) => entry,
+                    None => return Ok(()),
+                };
+                if corpus {
+                    entry.frequency = rank;
+                }
+                Self::update_frequency_entry(&mut entry, list_id, &tx)?;
+                rank += 1;
+                Ok(())
+            })?;
         }
+
+        bar.finish_and_clear();
+
+        if tx.commit().is_err() {
+            bail!("Unable to commit transaction");
+        }
+
+        println!("Finished importing frequency list.");
         Ok(())
     }
 
@@ -206,7 +814,9 @@ impl DictDb {
     }
 
     pub fn get_all_dicts(&self) -> rusqlite::Result<Vec<DbDictionary>> {
-        let mut stmt = self.conn.conn.prepare("SELECT * FROM dicts")?;
+        let mut stmt = self.conn.conn.prepare(
+            "SELECT id, title, priority, fallback, enabled, revision, author, url, description, sequenced FROM dicts",
+        )?;
         let mut rows = stmt.query([])?;
 
         let mut dicts = Vec::new();
@@ -217,22 +827,37 @@ impl DictDb {
                 priority: row.get(2)?,
                 fallback: row.get(3)?,
                 enabled: row.get(4)?,
+                revision: row.get(5)?,
+                author: row.get(6)?,
+                url: row.get(7)?,
+                description: row.get(8)?,
+                sequenced: row.get(9)?,
             })
         }
 
         Ok(dicts)
     }
 
-    pub fn validate_yomichan(path: &Path, is_freq: bool) -> bool {
-        let is_dir = path.is_dir();
-        let has_index = path.join("index.json").exists();
-        let has_termbanks;
+    // Fails with what is missing when the path doesn't hold a yomichan dictionary,
+    // or a frequency list for `is_freq`
+    fn validate_yomichan(source: &YomichanSource, path: &Path, is_freq: bool) -> Result<()> {
+        if !source.contains("index.json") {
+            bail!("{} has no index.json, it isn't a yomichan dictionary", path.display());
+        }
         if is_freq {
-            has_termbanks = path.join("term_meta_bank_1.json").exists();
-        } else {
-            has_termbanks = path.join("term_bank_1.json").exists();
+            if !source.contains("term_meta_bank_1.json") {
+                bail!("{} has no term_meta_bank_1.json to read frequencies from", path.display());
+            }
+        } else if !(source.contains("term_bank_1.json")
+            || source.contains("kanji_bank_1.json")
+            || source.contains("term_meta_bank_1.json"))
+        {
+            bail!(
+                "{} has no term_bank_1.json, kanji_bank_1.json or term_meta_bank_1.json to import",
+                path.display()
+            );
         }
-        is_dir && has_index && has_termbanks
+        Ok(())
     }
 
     fn get_dict_id(title: &str, tx: &Transaction) -> rusqlite::Result<i64> {
@@ -245,118 +870,926 @@ impl DictDb {
         Ok(dict_id)
     }
 
-    fn insert_dict(title: &str, tx: &Transaction) -> rusqlite::Result<i64> {
+    pub fn delete_dict(&mut self, title: &str) -> Result<()> {
+        let tx = self.conn.get_transaction()?;
+        let dict_id = match Self::get_dict_id(title, &tx) {
+            Ok(dict_id) => dict_id,
+            Err(_) => bail!("There is no dictionary named {}", title),
+        };
+        Self::delete_dict_data(dict_id, &tx)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_dict_data(dict_id: i64, tx: &Transaction) -> rusqlite::Result<()> {
+        tx.execute(
+            "DELETE FROM freq WHERE list_id IN (SELECT id FROM freq_lists WHERE dict_id = ?1)",
+            params![dict_id],
+        )?;
+        for table in ["entries", "tags", "kanji", "pitch", "freq_lists"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE dict_id = ?1", table),
+                params![dict_id],
+            )?;
+        }
+        tx.execute("DELETE FROM dicts WHERE id = ?1", params![dict_id])?;
+        Ok(())
+    }
+
+    fn get_revision(dict_id: i64, tx: &Transaction) -> rusqlite::Result<String> {
+        tx.query_row(
+            "SELECT revision FROM dicts WHERE id = ?1",
+            params![dict_id],
+            |r| r.get(0),
+        )
+    }
+
+    fn insert_dict(title: &str, index: &YomichanDict, tx: &Transaction) -> rusqlite::Result<i64> {
         tx.query_row::<i64, _, _>(
-            "INSERT INTO dicts (title) VALUES (?1) RETURNING id",
-            params![title],
+            "INSERT INTO dicts (title, revision, author, url, description, sequenced) VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING id",
+            params![
+                title,
+                index.revision,
+                index.author,
+                index.url,
+                index.description,
+                index.sequenced
+            ],
             |r| r.get(0),
         )
     }
 
-    fn insert_entry(
-        entry: YomichanEntryV1,
+    // The first sense id of an import. Stored senses never exceed the id of their
+    // first row, so counting on from the highest id can't reuse one
+    fn first_sense(tx: &Transaction) -> rusqlite::Result<i64> {
+        tx.query_row("SELECT COALESCE(MAX(id), 0) + 1 FROM entries", [], |row| row.get(0))
+    }
+
+    // One row per meaning, the meanings of an entry share a sense id that is unique
+    // across the table, `next_sense` is carried from batch to batch
+    fn insert_entries(
+        entries: &[TermEntry],
         dict_id: i64,
+        next_sense: &mut i64,
         tx: &Transaction,
     ) -> rusqlite::Result<()> {
-        for meaning in entry.meanings {
-            tx.execute(
-                "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES (?1, ?2, ?3, ?4)",
-                params![entry.expression, entry.reading, meaning, dict_id],
+        let mut rows = vec![];
+        for entry in entries {
+            let sense = *next_sense;
+            *next_sense += 1;
+            for (i, meaning) in entry.meanings.iter().enumerate() {
+                let glossary = entry.glossaries.get(i).and_then(Option::as_ref);
+                rows.push((entry, meaning, sense, glossary));
+            }
+        }
+
+        for chunk in rows.chunks(INSERT_BATCH_ROWS) {
+            let sql = format!(
+                "INSERT INTO entries (expression, reading, meaning, dict_id, definition_tags, term_tags, rules, popularity, sequence, traditional, simplified, pinyin, sense, glossary)
+                VALUES {}",
+                vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+            );
+            let mut values: Vec<&dyn ToSql> = Vec::with_capacity(chunk.len() * 14);
+            for (entry, meaning, sense, glossary) in chunk {
+                values.extend_from_slice(&[
+                    &entry.expression,
+                    &entry.reading,
+                    *meaning,
+                    &dict_id,
+                    &entry.definition_tags,
+                    &entry.term_tags,
+                    &entry.rule_identifiers,
+                    &entry.popularity,
+                    &entry.sequence,
+                    &entry.traditional,
+                    &entry.simplified,
+                    &entry.pinyin,
+                    sense,
+                    glossary,
+                ]);
+            }
+            tx.prepare_cached(&sql)?.execute(values.as_slice())?;
+        }
+
+        Ok(())
+    }
+
+    fn insert_kanji(kanji: YomichanKanji, dict_id: i64, tx: &Transaction) -> rusqlite::Result<()> {
+        tx.prepare_cached(
+            "INSERT INTO kanji (character, onyomi, kunyomi, tags, meanings, stats, dict_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+                kanji.character,
+                kanji.onyomi,
+                kanji.kunyomi,
+                kanji.tags,
+                kanji.meanings.join("\n"),
+                serde_json::Value::Object(kanji.stats).to_string(),
+                dict_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn lookup_kanji(&self, character: &str) -> rusqlite::Result<Option<DbKanji>> {
+        let mut stmt = self.conn.conn.prepare_cached(
+            "SELECT character, onyomi, kunyomi, meanings, stats, dict_id FROM kanji
+            INNER JOIN dicts ON kanji.dict_id = dicts.id
+            WHERE enabled = 1 AND character = ?1
+            ORDER BY priority DESC LIMIT 1",
+        )?;
+        let mut rows = stmt.query_map(params![character], |row| {
+            let meanings: String = row.get(3)?;
+            let stats: String = row.get(4)?;
+            Ok(DbKanji {
+                character: row.get(0)?,
+                onyomi: row.get(1)?,
+                kunyomi: row.get(2)?,
+                meanings: meanings.lines().map(str::to_string).collect(),
+                stats: serde_json::from_str(&stats).unwrap_or_default(),
+                dict_id: row.get(5)?,
+            })
+        })?;
+        rows.next().transpose()
+    }
+
+    // Returns how many accents were skipped for not having a single downstep position
+    fn insert_pitch(
+        expression: &str,
+        pitch: PitchData,
+        dict_id: i64,
+        tx: &Transaction,
+    ) -> rusqlite::Result<usize> {
+        let mut skipped = 0;
+        for accent in pitch.pitches {
+            let position = match accent.position.downstep() {
+                Some(position) => position,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            tx.prepare_cached(
+                "INSERT INTO pitch (expression, reading, position, tags, dict_id) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                    expression,
+                    pitch.reading,
+                    position as i64,
+                    accent.tags.join(" "),
+                    dict_id
+                ],
             )?;
         }
+        Ok(skipped)
+    }
+
+    // Downstep positions of a word, from the highest priority dictionary that has any
+    pub fn lookup_pitch(&self, expression: &str, reading: &str) -> rusqlite::Result<Vec<usize>> {
+        let mut stmt = self.conn.conn.prepare_cached(
+            "SELECT position, dict_id FROM pitch
+            INNER JOIN dicts ON pitch.dict_id = dicts.id
+            WHERE enabled = 1 AND expression = ?1 AND reading = ?2
+            ORDER BY priority DESC, pitch.id ASC",
+        )?;
+        let rows = stmt.query_map(params![expression, reading], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+        })?;
 
+        let mut positions = vec![];
+        let mut source_dict = None;
+        for row in rows {
+            let (position, dict_id) = row?;
+            if *source_dict.get_or_insert(dict_id) != dict_id {
+                break;
+            }
+            positions.push(position as usize);
+        }
+        Ok(positions)
+    }
+
+    fn insert_tag(tag: YomichanTag, dict_id: i64, tx: &Transaction) -> rusqlite::Result<()> {
+        tx.prepare_cached(
+            "INSERT INTO tags (name, category, sort_order, notes, score, dict_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![tag.name, tag.category, tag.order, tag.notes, tag.score, dict_id])?;
         Ok(())
     }
 
+    // Every tag of a dictionary by name, loaded once to format many entries or kanji
+    pub fn get_dict_tags(&self, dict_id: i64) -> rusqlite::Result<HashMap<String, DbTag>> {
+        let mut stmt = self
+            .conn
+            .conn
+            .prepare_cached("SELECT name, category, notes FROM tags WHERE dict_id = ?1 ORDER BY id")?;
+        let mut tags = HashMap::new();
+        let mut rows = stmt.query(params![dict_id])?;
+        while let Some(row) = rows.next()? {
+            let tag = DbTag {
+                name: row.get(0)?,
+                category: row.get(1)?,
+                notes: row.get(2)?,
+            };
+            tags.entry(tag.name.clone()).or_insert(tag);
+        }
+        Ok(tags)
+    }
+
     pub fn update_frequency_entry(
         entry: &mut YomichanFrequencyEntry,
-        avg: bool,
+        list_id: i64,
         tx: &Transaction,
     ) -> rusqlite::Result<()> {
-        let word = &entry.expression;
-        let new_freq = if avg {
-            let avg_freq = tx.query_row::<i64, _, _>(
-                "SELECT freq FROM freq WHERE word = ?1",
-                params![word],
+        // a word listed more than once keeps its best rank
+        tx.prepare_cached(
+            "INSERT INTO freq (list_id, word, reading, freq, display) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (list_id, word, reading) DO UPDATE SET freq = MIN(freq, ?4)",
+        )?
+        .execute(params![
+            list_id,
+            entry.expression,
+            entry.reading,
+            entry.frequency,
+            entry.display
+        ])?;
+        Ok(())
+    }
+
+    fn get_frequency_list_id(name: &str, tx: &Transaction) -> rusqlite::Result<i64> {
+        tx.query_row(
+            "SELECT id FROM freq_lists WHERE name = ?1",
+            params![name],
+            |r| r.get(0),
+        )
+    }
+
+    // `dict_id` is set for the frequencies that came with a dictionary, they are exported
+    // and deleted along with it
+    fn insert_frequency_list(
+        name: &str,
+        mode: &str,
+        dict_id: Option<i64>,
+        tx: &Transaction,
+    ) -> rusqlite::Result<i64> {
+        tx.query_row(
+            "INSERT INTO freq_lists (name, mode, dict_id) VALUES (?1, ?2, ?3) RETURNING id",
+            params![name, mode, dict_id],
+            |r| r.get(0),
+        )
+    }
+
+    pub fn get_frequency_lists(&self) -> rusqlite::Result<Vec<DbFrequencyList>> {
+        let mut stmt = self.conn.conn.prepare(
+            "SELECT name, enabled, (SELECT COUNT(*) FROM freq WHERE list_id = freq_lists.id) FROM freq_lists ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(DbFrequencyList {
+                name: row.get(0)?,
+                enabled: row.get(1)?,
+                words: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn rename_frequency_list(&self, old: &str, new: &str) -> Result<()> {
+        check_frequency_list_name(new)?;
+        let updated = self.conn.conn.execute(
+            "UPDATE freq_lists SET name = ?2 WHERE name = ?1",
+            params![old, new],
+        )?;
+        if updated == 0 {
+            bail!("There is no frequency list named {}", old);
+        }
+        Ok(())
+    }
+
+    // A single list picked by `freq_ranking` has to exist, otherwise nothing would be ranked
+    pub fn check_frequency_ranking(&self, ranking: &FrequencyRanking) -> Result<()> {
+        if let FrequencyRanking::List(name) = ranking {
+            let exists: bool = self.conn.conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM freq_lists WHERE name = ?1)",
+                params![name],
                 |r| r.get(0),
-            );
-            if let Ok(avg_freq) = avg_freq {
-                (avg_freq + entry.frequency) / 2
-            } else {
-                entry.frequency
+            )?;
+            if !exists {
+                bail!(
+                    "freq_ranking is set to {}, which is neither {} nor the name of a frequency list",
+                    name,
+                    FrequencyRanking::RESERVED.join(", ")
+                );
             }
-        } else {
-            entry.frequency
+        }
+        Ok(())
+    }
+
+    pub fn set_frequency_list_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let updated = self.conn.conn.execute(
+            "UPDATE freq_lists SET enabled = ?2 WHERE name = ?1",
+            params![name, enabled],
+        )?;
+        if updated == 0 {
+            bail!("There is no frequency list named {}", name);
+        }
+        Ok(())
+    }
+
+    pub fn delete_frequency_list(&mut self, name: &str) -> Result<()> {
+        let tx = self.conn.get_transaction()?;
+        let list_id = match Self::get_frequency_list_id(name, &tx) {
+            Ok(list_id) => list_id,
+            Err(_) => bail!("There is no frequency list named {}", name),
         };
-        tx.execute(
-            "INSERT INTO freq (word, freq) VALUES (?2, ?1) ON CONFLICT (word) DO UPDATE SET freq = ?1 WHERE word = ?2",
-            params![new_freq, word],
+        tx.execute("DELETE FROM freq WHERE list_id = ?1", params![list_id])?;
+        tx.execute("DELETE FROM freq_lists WHERE id = ?1", params![list_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Writes an imported dictionary back out as a yomichan archive
+    pub fn export_dict(&self, title: &str, path: &Path) -> Result<()> {
+        let dict = match self
+            .get_all_dicts()?
+            .into_iter()
+            .find(|dict| dict.title == title)
+        {
+            Some(dict) => dict,
+            None => bail!("There is no dictionary named {}", title),
+        };
+        let conn = &self.conn.conn;
+        let mut writer = YomichanWriter::create(path)?;
+
+        let mut index = json!({
+            "title": dict.title,
+            "format": 3,
+            "revision": dict.revision,
+            "sequenced": dict.sequenced,
+        });
+        for (key, value) in [
+            ("author", &dict.author),
+            ("url", &dict.url),
+            ("description", &dict.description),
+        ] {
+            if !value.is_empty() {
+                index[key] = json!(value);
+            }
+        }
+        // the frequencies that came with the dictionary
+        let frequency_list = conn
+            .query_row(
+                "SELECT id, mode FROM freq_lists WHERE dict_id = ?1",
+                params![dict.id],
+                |r| Ok((r.get::<_, i64>(0)?, r.get::<_, String>(1)?)),
+            )
+            .optional()?
+            .map(|(list_id, mode)| {
+                index["frequencyMode"] = json!(mode);
+                list_id
+            });
+        writer.write_json("index.json", &index)?;
+
+        let mut banks = writer.banks("tag_bank_");
+        let mut stmt = conn.prepare(
+            "SELECT name, category, sort_order, notes, score FROM tags WHERE dict_id = ?1 ORDER BY id",
         )?;
+        let mut rows = stmt.query(params![dict.id])?;
+        while let Some(row) = rows.next()? {
+            banks.push(json!([
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+            ]))?;
+        }
+        banks.finish()?;
+
+        // entries hold one meaning each, the ones of the same sense become one row again
+        let mut banks = writer.banks("term_bank_");
+        let mut stmt = conn.prepare(
+            "SELECT expression, reading, definition_tags, rules, popularity, meaning, sequence, term_tags,
+                sense, glossary, traditional
+            FROM entries WHERE dict_id = ?1 ORDER BY id",
+        )?;
+        let mut rows = stmt.query(params![dict.id])?;
+        let mut term: Option<(Value, Option<i64>, String)> = None;
+        while let Some(row) = rows.next()? {
+            let sense: Option<i64> = row.get(8)?;
+            let traditional: String = row.get(10)?;
+            // structured content is written back as it was imported
+            let meaning = match row.get::<_, Option<String>>(9)? {
+                Some(glossary) => serde_json::from_str(&glossary)?,
+                None => json!(row.get::<_, String>(5)?),
+            };
+            let row = json!([
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, f64>(4)?,
+                [meaning],
+                row.get::<_, Option<i64>>(6)?.unwrap_or_default(),
+                row.get::<_, String>(7)?,
+            ]);
+            match term.as_mut() {
+                Some((term, term_sense, term_traditional))
+                    if same_term(term, &row)
+                        && *term_sense == sense
+                        && *term_traditional == traditional =>
+                {
+                    let meaning = row[5][0].clone();
+                    if let Some(meanings) = term[5].as_array_mut() {
+                        meanings.push(meaning);
+                    }
+                }
+                _ => {
+                    if let Some(term) = term.replace((row, sense, traditional)) {
+                        push_term(&mut banks, term)?;
+                    }
+                }
+            }
+        }
+        if let Some(term) = term {
+            push_term(&mut banks, term)?;
+        }
+        banks.finish()?;
+
+        let mut banks = writer.banks("term_meta_bank_");
+        let mut stmt = conn.prepare(
+            "SELECT expression, reading, position, tags FROM pitch WHERE dict_id = ?1
+            ORDER BY expression, reading, id",
+        )?;
+        let mut rows = stmt.query(params![dict.id])?;
+        let mut pitch: Option<Value> = None;
+        while let Some(row) = rows.next()? {
+            let expression: String = row.get(0)?;
+            let reading: String = row.get(1)?;
+            let tags: String = row.get(3)?;
+            let mut accent = json!({ "position": row.get::<_, i64>(2)? });
+            if !tags.is_empty() {
+                accent["tags"] = json!(tags.split(' ').collect::<Vec<_>>());
+            }
+            match pitch.as_mut() {
+                Some(pitch) if pitch[0] == expression && pitch[2]["reading"] == reading => {
+                    if let Some(pitches) = pitch[2]["pitches"].as_array_mut() {
+                        pitches.push(accent);
+                    }
+                }
+                _ => {
+                    let row =
+                        json!([expression, "pitch", { "reading": reading, "pitches": [accent] }]);
+                    if let Some(pitch) = pitch.replace(row) {
+                        banks.push(pitch)?;
+                    }
+                }
+            }
+        }
+        if let Some(pitch) = pitch {
+            banks.push(pitch)?;
+        }
+        if let Some(list_id) = frequency_list {
+            Self::push_frequencies(&mut banks, list_id, conn)?;
+        }
+        banks.finish()?;
+
+        let mut banks = writer.banks("kanji_bank_");
+        let mut stmt = conn.prepare(
+            "SELECT character, onyomi, kunyomi, tags, meanings, stats FROM kanji WHERE dict_id = ?1 ORDER BY id",
+        )?;
+        let mut rows = stmt.query(params![dict.id])?;
+        while let Some(row) = rows.next()? {
+            let meanings: String = row.get(4)?;
+            let stats: String = row.get(5)?;
+            banks.push(json!([
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                meanings.lines().collect::<Vec<_>>(),
+                serde_json::from_str::<Value>(&stats).unwrap_or_else(|_| json!({})),
+            ]))?;
+        }
+        banks.finish()?;
+
+        writer.finish()?;
+        println!("Exported {} to {}", title, path.display());
         Ok(())
     }
 
+    // Writes a frequency list as a yomichan frequency dictionary
+    pub fn export_frequency_list(&self, name: &str, path: &Path) -> Result<()> {
+        let conn = &self.conn.conn;
+        let (list_id, mode): (i64, String) = match conn.query_row(
+            "SELECT id, mode FROM freq_lists WHERE name = ?1",
+            params![name],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        ) {
+            Ok(list) => list,
+            Err(_) => bail!("There is no frequency list named {}", name),
+        };
+        let mut writer = YomichanWriter::create(path)?;
+        writer.write_json(
+            "index.json",
+            &json!({
+                "title": name,
+                "format": 3,
+                "revision": "1",
+                "frequencyMode": mode,
+            }),
+        )?;
+
+        let mut banks = writer.banks("term_meta_bank_");
+        Self::push_frequencies(&mut banks, list_id, conn)?;
+        banks.finish()?;
+
+        writer.finish()?;
+        println!("Exported {} to {}", name, path.display());
+        Ok(())
+    }
+
+    fn push_frequencies(banks: &mut BankWriter, list_id: i64, conn: &Connection) -> Result<()> {
+        let mut stmt = conn.prepare(
+            "SELECT word, reading, freq, display FROM freq WHERE list_id = ?1 ORDER BY freq, id",
+        )?;
+        let mut rows = stmt.query(params![list_id])?;
+        while let Some(row) = rows.next()? {
+            let word: String = row.get(0)?;
+            let reading: String = row.get(1)?;
+            let mut frequency = json!({ "value": row.get::<_, i64>(2)? });
+            if let Some(display) = row.get::<_, Option<String>>(3)? {
+                frequency["displayValue"] = json!(display);
+            }
+            let data = if reading.is_empty() {
+                frequency
+            } else {
+                json!({ "reading": reading, "frequency": frequency })
+            };
+            banks.push(json!([word, "freq", data]))?;
+        }
+        Ok(())
+    }
+
+    // Fills in the frequencies of every entry from each enabled list, preferring the ones
+    // specific to its reading, with one query per batch of words
+    fn add_frequencies(
+        &self,
+        entries: &mut [DbDictEntry],
+        ranking: &FrequencyRanking,
+    ) -> rusqlite::Result<()> {
+        let mut words = entries
+            .iter()
+            .map(|entry| entry.expression.as_str())
+            .collect::<Vec<_>>();
+        words.sort_unstable();
+        words.dedup();
+
+        // word -> (reading, frequency) in list order, reading specific ones first
+        let mut found: HashMap<String, Vec<(String, DbFrequency)>> = HashMap::new();
+        for chunk in words.chunks(FREQUENCY_BATCH_WORDS) {
+            let sql = format!(
+                "SELECT word, reading, name, freq, display FROM freq
+                INNER JOIN freq_lists ON freq.list_id = freq_lists.id
+                WHERE enabled = 1 AND word IN ({})
+                ORDER BY freq_lists.id, reading = ''",
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut stmt = self.conn.conn.prepare_cached(&sql)?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(chunk))?;
+            while let Some(row) = rows.next()? {
+                let frequency = DbFrequency {
+                    list: row.get(2)?,
+                    value: row.get(3)?,
+                    display: row.get(4)?,
+                };
+                found
+                    .entry(row.get(0)?)
+                    .or_default()
+                    .push((row.get(1)?, frequency));
+            }
+        }
+
+        for entry in entries {
+            let mut frequencies: Vec<DbFrequency> = vec![];
+            let candidates = found.get(&entry.expression).into_iter().flatten();
+            for (reading, frequency) in candidates {
+                let applies = reading.is_empty() || *reading == entry.reading;
+                if applies && frequencies.iter().all(|other| other.list != frequency.list) {
+                    frequencies.push(frequency.clone());
+                }
+            }
+            entry.rank = ranking.rank(&frequencies);
+            entry.frequencies = frequencies;
+        }
+        Ok(())
+    }
+
+    fn query_entries(
+        &self,
+        from: &str,
+        condition: &str,
+        params: &[(&str, &dyn ToSql)],
+        sort_freq: bool,
+        ranking: &FrequencyRanking,
+    ) -> rusqlite::Result<Vec<DbDictEntry>> {
+        let mut stmt = self.conn.conn.prepare(&entries_query(from, condition))?;
+        let mut entries = stmt
+            .query_map(params, entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        self.add_frequencies(&mut entries, ranking)?;
+
+        if sort_freq {
+            // unknown words go last, the sort is stable so dictionary order is kept otherwise
+            entries.sort_by_key(|entry| {
+                (
+                    Reverse(entry.priority),
+                    entry.rank.is_none(),
+                    entry.rank.unwrap_or_default(),
+                )
+            });
+        }
+
+        Ok(entries)
+    }
+
     fn _lookup_word(
         &self,
         word: &str,
         fallback: bool,
         sort_freq: bool,
+        ranking: &FrequencyRanking,
         is_japanese: bool,
     ) -> rusqlite::Result<Vec<DbDictEntry>> {
-        let lookup_column = if all_kana(word) && is_japanese {
-            "reading"
+        let condition = if !is_japanese {
+            // chinese words can be looked up in either script
+            "(expression = :word OR traditional = :word)"
+        } else if all_kana(word) {
+            "reading = :word"
         } else {
-            "expression"
+            "expression = :word"
         };
-        let sort_sql = if sort_freq {
-            ", (CASE WHEN freq.freq IS NULL then 1 ELSE 0 END), freq ASC"
-        } else {
-            ""
-        };
-        let sql = format!(
-            "SELECT entries.*, freq.freq FROM entries 
-            INNER JOIN dicts ON entries.dict_id = dicts.id 
-            LEFT JOIN freq ON entries.expression = freq.word
-            WHERE enabled = 1 AND fallback = :fallback AND {} = :word
-            ORDER BY priority DESC{}",
-            lookup_column, sort_sql
-        );
-        let mut stmt = self.conn.conn.prepare(&sql)?;
-        let rows = stmt.query_map(
-            &[
-                (":word", word),
-                (":fallback", &(fallback as i32).to_string()),
-            ],
-            |row| {
-                Ok(DbDictEntry {
-                    id: row.get(0)?,
-                    expression: row.get(1)?,
-                    reading: row.get(2)?,
-                    meaning: row.get(3)?,
-                    dict_id: row.get(4)?,
-                })
-            },
-        )?;
-        let mut entries: Vec<DbDictEntry> = vec![];
-        for row in rows {
-            entries.push(row?);
+        self.query_entries(
+            ENTRIES_FROM,
+            condition,
+            &[(":word", &word), (":fallback", &fallback)],
+            sort_freq,
+            ranking,
+        )
+    }
+
+    // The words that an enabled dictionary has entries for, matched the way lookup_word
+    // matches them but a batch at a time and without loading the entries
+    fn known_words(
+        &self,
+        words: &[&str],
+        is_japanese: bool,
+    ) -> rusqlite::Result<HashSet<String>> {
+        let mut words = words.to_vec();
+        words.sort_unstable();
+        words.dedup();
+
+        let mut known = HashSet::new();
+        for chunk in words.chunks(KNOWN_BATCH_WORDS) {
+            let (kana, other): (Vec<&str>, Vec<&str>) = if is_japanese {
+                chunk.iter().partition(|word| all_kana(word))
+            } else {
+                (vec![], chunk.to_vec())
+            };
+            // chinese words can be looked up in either script
+            let traditional = if is_japanese { vec![] } else { other.clone() };
+
+            let mut selects = vec![];
+            let mut params = vec![];
+            for (column, words) in [
+                ("reading", kana),
+                ("expression", other),
+                ("traditional", traditional),
+            ] {
+                if words.is_empty() {
+                    continue;
+                }
+                selects.push(format!(
+                    "SELECT {0} FROM entries INNER JOIN dicts ON entries.dict_id = dicts.id
+                    WHERE enabled = 1 AND {0} IN ({1})",
+                    column,
+                    vec!["?"; words.len()].join(", ")
+                ));
+                params.extend(words);
+            }
+            let mut stmt = self.conn.conn.prepare(&selects.join(" UNION "))?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+            while let Some(row) = rows.next()? {
+                known.insert(row.get(0)?);
+            }
         }
-        Ok(entries)
+        Ok(known)
     }
 
     pub fn lookup_word(
         &self,
         word: &str,
-        sort_freq: bool,
+        lookup_config: &LookupConfig,
         is_japanese: bool,
     ) -> rusqlite::Result<Vec<DbDictEntry>> {
-        let mut entries = self._lookup_word(word, false, sort_freq, is_japanese)?;
+        let sort_freq = lookup_config.sort_freq;
+        let ranking = &lookup_config.freq_ranking;
+        let mut entries = self._lookup_word(word, false, sort_freq, ranking, is_japanese)?;
         // fallback
         if entries.is_empty() {
-            entries = self._lookup_word(word, true, sort_freq, is_japanese)?;
+            entries = self._lookup_word(word, true, sort_freq, ranking, is_japanese)?;
         }
 
         Ok(entries)
     }
+
+    fn _lookup_term(
+        &self,
+        expression: &str,
+        reading: Option<&str>,
+        fallback: bool,
+        lookup_config: &LookupConfig,
+    ) -> rusqlite::Result<Vec<DbDictEntry>> {
+        let sort_freq = lookup_config.sort_freq;
+        let ranking = &lookup_config.freq_ranking;
+        match reading {
+            // kana terms are often stored without a reading
+            Some(reading) => self.query_entries(
+                ENTRIES_BY_DICT_FROM,
+                "expression = :expression AND (reading = :reading OR (reading = '' AND expression = :reading))",
+                &[
+                    (":expression", &expression),
+                    (":reading", &reading),
+                    (":fallback", &fallback),
+                ],
+                sort_freq,
+                ranking,
+            ),
+            None => self.query_entries(
+                ENTRIES_BY_DICT_FROM,
+                "expression = :expression",
+                &[(":expression", &expression), (":fallback", &fallback)],
+                sort_freq,
+                ranking,
+            ),
+        }
+    }
+
+    // Looks up the exact term, optionally restricted to one reading to tell homographs
+    // apart, with the entries of every dictionary grouped by expression and reading
+    pub fn lookup_term(
+        &self,
+        expression: &str,
+        reading: Option<&str>,
+        lookup_config: &LookupConfig,
+    ) -> rusqlite::Result<Vec<DbTermGroup>> {
+        let mut entries = self._lookup_term(expression, reading, false, lookup_config)?;
+        // fallback
+        if entries.is_empty() {
+            entries = self._lookup_term(expression, reading, true, lookup_config)?;
+        }
+
+        Ok(group_terms(entries))
+    }
+
+    // Full text search over expressions, readings and meanings. `*` and `?` are wildcards,
+    // a reverse search finds the terms whose meaning has every word of the query in it
+    pub fn search(
+        &self,
+        query: &str,
+        reverse: bool,
+        limit: usize,
+        lookup_config: &LookupConfig,
+    ) -> Result<Vec<DbTermGroup>> {
+        let query = query.trim();
+        if query.is_empty() {
+            bail!("Empty search query");
+        }
+        let fts_join = "FROM entries_fts
+            INNER JOIN entries ON entries.id = entries_fts.rowid
+            INNER JOIN dicts ON entries.dict_id = dicts.id
+            WHERE enabled = 1 AND entries_fts MATCH :query";
+        let fts_score = "bm25(entries_fts)";
+        let (matches, score, pattern) = if reverse {
+            let words = query.split_whitespace().map(fts_term).collect::<Vec<_>>();
+            (fts_join, fts_score, format!("meaning : ({})", words.join(" ")))
+        } else {
+            let stem = query.strip_suffix('*').unwrap_or(query);
+            if stem.is_empty() {
+                bail!("Search query needs more than a wildcard");
+            }
+            if stem.contains(['*', '?']) {
+                // the full text index only knows whole words and prefixes, any other
+                // wildcard falls back to scanning every entry with GLOB
+                (
+                    "FROM entries INNER JOIN dicts ON entries.dict_id = dicts.id
+                    WHERE enabled = 1
+                        AND (entries.expression GLOB :query OR entries.reading GLOB :query)",
+                    "0",
+                    query.to_string(),
+                )
+            } else {
+                (
+                    fts_join,
+                    fts_score,
+                    format!("{{expression reading}} : {}", fts_term(query)),
+                )
+            }
+        };
+        let limit = limit as i64;
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":query", &pattern), (":limit", &limit)];
+        // terms are listed by relevance, or for term searches where every match is equally
+        // relevant, common words first
+        let by_frequency = !reverse && lookup_config.sort_freq;
+        let rank = if by_frequency {
+            let (rank, condition) = match &lookup_config.freq_ranking {
+                FrequencyRanking::Min => ("MIN(freq)", ""),
+                FrequencyRanking::HarmonicMean => (
+                    "CAST(ROUND(COUNT(*) / SUM(1.0 / MAX(freq, 1))) AS INTEGER)",
+                    "",
+                ),
+                FrequencyRanking::List(name) => {
+                    params.push((":list", name));
+                    ("MIN(freq)", "AND freq_lists.name = :list")
+                }
+            };
+            // one frequency per list, the one of the entry's reading if there is one
+            format!(
+                "(SELECT {} FROM freq INNER JOIN freq_lists ON freq.list_id = freq_lists.id
+                WHERE freq_lists.enabled = 1 {} AND freq.word = entries.expression
+                    AND (freq.reading = entries.reading OR (freq.reading = '' AND NOT EXISTS (
+                        SELECT 1 FROM freq AS specific WHERE specific.list_id = freq.list_id
+                            AND specific.word = freq.word AND specific.reading = entries.reading
+                    ))))",
+                rank, condition
+            )
+        } else {
+            "NULL".to_string()
+        };
+        let term_order = if by_frequency {
+            "MIN(rank) IS NULL, MIN(rank), LENGTH(expression),"
+        } else {
+            ""
+        };
+
+        // only the entries of the first `limit` terms are loaded
+        let sql = format!(
+            "WITH matches AS MATERIALIZED (
+                SELECT entries.id, entries.expression,
+                    IIF(entries.reading = '', entries.expression, entries.reading) AS term_reading,
+                    {} AS score, priority, {} AS rank
+                {}
+            ), terms AS (
+                SELECT expression, term_reading, ROW_NUMBER() OVER (
+                    ORDER BY {} MIN(score), MAX(priority) DESC, MIN(id)
+                ) AS position
+                FROM matches GROUP BY expression, term_reading
+                ORDER BY position LIMIT :limit
+            )
+            SELECT {} FROM terms
+            INNER JOIN matches USING (expression, term_reading)
+            INNER JOIN entries ON entries.id = matches.id
+            INNER JOIN dicts ON entries.dict_id = dicts.id
+            ORDER BY terms.position, matches.score, matches.priority DESC, entries.id",
+            score, rank, matches, term_order, ENTRY_COLUMNS
+        );
+        let mut stmt = self.conn.conn.prepare(&sql)?;
+        let mut entries = stmt
+            .query_map(params.as_slice(), entry_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        self.add_frequencies(&mut entries, &lookup_config.freq_ranking)?;
+        Ok(group_terms(entries))
+    }
+}
+
+// Groups entries by expression and reading in the order they come in
+fn group_terms(entries: Vec<DbDictEntry>) -> Vec<DbTermGroup> {
+    let mut groups: Vec<DbTermGroup> = vec![];
+    for entry in entries {
+        let reading = if entry.reading.is_empty() {
+            entry.expression.clone()
+        } else {
+            entry.reading.clone()
+        };
+        match groups
+            .iter_mut()
+            .find(|group| group.expression == entry.expression && group.reading == reading)
+        {
+            Some(group) => group.entries.push(entry),
+            None => groups.push(DbTermGroup {
+                expression: entry.expression.clone(),
+                reading,
+                entries: vec![entry],
+            }),
+        }
+    }
+    groups
+}
+
+// Quotes a word of a search query for FTS5, a trailing `*` makes it a prefix search
+fn fts_term(word: &str) -> String {
+    match word.strip_suffix('*') {
+        Some(prefix) => format!("\"{}\"*", prefix.replace('"', "\"\"")),
+        None => format!("\"{}\"", word.replace('"', "\"\"")),
+    }
 }
 
 impl DictConn {
@@ -365,68 +1798,489 @@ impl DictConn {
         if let Some(basedirs) = basedirs {
             let path = basedirs.data_dir().join("ace");
             fs::create_dir_all(&path)?;
-            let db_path = path.join("dict.db");
-            let new = !db_path.exists();
-            let conn = Connection::open(db_path)?;
+            let conn = Connection::open(path.join("dict.db"))?;
 
-            Ok(DictConn { conn, new })
+            Ok(DictConn { conn })
         } else {
             Err(anyhow!("Could not find data directory"))
         }
     }
 
-    pub fn get_transaction(&mut self) -> rusqlite::Result<Transaction> {
+    pub fn get_transaction(&mut self) -> rusqlite::Result<Transaction<'_>> {
         self.conn.transaction()
     }
+}
 
-    pub fn setup_schema(&self) -> rusqlite::Result<()> {
-        self.conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS dicts (
-                  id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                  title           TEXT NOT NULL,
-                  priority        INTEGER DEFAULT 0,
-                  fallback        INTEGER DEFAULT 0,
-                  enabled         INTEGER DEFAULT 0
-            );
+pub fn lookup(dict_db: &DictDb, word: String) -> Result<Vec<LookupResult>> {
+    let config = get_config()?;
+    if config.is_japanese {
+        let deinflector = dict_db.deinflector(&config.deinflect)?;
+        return Ok(lookup_deinflected(dict_db, deinflector, word, &config.lookup)?);
+    }
 
-            CREATE TABLE IF NOT EXISTS entries (
-                  id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                  expression      TEXT NOT NULL,
-                  reading         TEXT,
-                  meaning         TEXT NOT NULL,
-                  dict_id         INTEGER NOT NULL,
-                  frequency       INTEGER DEFAULT 0,
-                  FOREIGN KEY(dict_id) REFERENCES dicts(id)
-            );
+    let mut results: Vec<LookupResult> = vec![];
+    let entries = dict_db.lookup_word(&word, &config.lookup, false)?;
+    if !entries.is_empty() {
+        results.push(LookupResult {
+            term: word,
+            reasons: vec![],
+            entries,
+        });
+    }
+    Ok(results)
+}
 
-            CREATE TABLE IF NOT EXISTS freq (
-                  id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                  word            TEXT NOT NULL UNIQUE,
-                  freq            INTEGER NOT NULL
-            );
+// Looks up exactly the term with this reading, without deinflecting it
+pub fn lookup_exact(
+    dict_db: &DictDb,
+    expression: &str,
+    reading: &str,
+    lookup_config: &LookupConfig,
+) -> rusqlite::Result<Vec<LookupResult>> {
+    let entries = dict_db
+        .lookup_term(expression, Some(reading), lookup_config)?
+        .into_iter()
+        .flat_map(|group| group.entries)
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        return Ok(vec![]);
+    }
+    Ok(vec![LookupResult {
+        term: expression.to_string(),
+        reasons: vec![],
+        entries,
+    }])
+}
 
-            CREATE INDEX IF NOT EXISTS word_idx ON entries(expression);
-            ",
-        )
+// Looks up every form a Japanese word may be deinflected from
+fn lookup_deinflected(
+    dict_db: &DictDb,
+    deinflector: &deinflect::Deinflector,
+    word: String,
+    lookup_config: &LookupConfig,
+) -> rusqlite::Result<Vec<LookupResult>> {
+    let mut results: Vec<LookupResult> = vec![];
+    for form in deinflector.deinflect(word).iter() {
+        let lookup_res = dict_db.lookup_word(&form.term, lookup_config, true)?;
+        let mut entries = vec![];
+        for entry in lookup_res {
+            // discard invalid deinflections, e.g. treating a noun as a verb stem
+            if !form.matches_rules(deinflector.entry_rule_flags(&entry.rules)) {
+                continue;
+            }
+            // the same entry can be reached through several deinflection paths
+            let seen = results
+                .iter()
+                .flat_map(|result| &result.entries)
+                .chain(&entries)
+                .any(|result: &DbDictEntry| result.id == entry.id);
+            if seen {
+                continue;
+            }
+            entries.push(entry);
+        }
+        if !entries.is_empty() {
+            results.push(LookupResult {
+                term: form.term.clone(),
+                reasons: form.reasons.clone(),
+                entries,
+            });
+        }
     }
+    Ok(results)
 }
 
-pub fn lookup(dict_db: &DictDb, word: String) -> Result<Vec<DbDictEntry>> {
-    let config = get_config()?;
-    let mut results: Vec<DbDictEntry> = vec![];
+// Most characters looked up at once when scanning, like yomichan's scan length
+const SCAN_LENGTH: usize = 16;
 
-    if config.is_japanese {
-        let deinflect_json = include_str!("../data/deinflect.json");
-        let deinflector = deinflect::Deinflector::new(deinflect_json);
-        let deinflected_forms = deinflector.deinflect(word);
+// The longest term found at some position of a text
+#[derive(Debug)]
+pub struct ScanResult {
+    // the matched text as it is written, e.g. an inflected verb
+    pub source: String,
+    pub results: Vec<LookupResult>,
+}
+
+// Terms don't span across these
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace()
+        || c.is_ascii_punctuation()
+        || "。、，．・：；！？…‥「」『』（）［］【】〈〉《》〔〕”“’‘".contains(c)
+}
 
-        for form in deinflected_forms {
-            let lookup_res = dict_db.lookup_word(&form.term, config.lookup.sort_freq, true)?;
-            results.extend(lookup_res);
+// The text starting at the character offset, longest first, down to one character
+fn scan_candidates(text: &str, offset: usize) -> Vec<String> {
+    let chars = text
+        .chars()
+        .skip(offset)
+        .take_while(|&c| !is_delimiter(c))
+        .take(SCAN_LENGTH)
+        .collect::<Vec<_>>();
+    (1..=chars.len())
+        .rev()
+        .map(|len| chars[..len].iter().collect())
+        .collect()
+}
+
+// The text from the character offset along with the forms it may be deinflected from,
+// longest first
+fn scan_forms(
+    deinflector: Option<&deinflect::Deinflector>,
+    text: &str,
+    offset: usize,
+) -> Vec<(String, Vec<String>)> {
+    scan_candidates(text, offset)
+        .into_iter()
+        .map(|source| {
+            let forms = match deinflector {
+                Some(deinflector) => deinflector
+                    .deinflect(source.clone())
+                    .iter()
+                    .map(|form| form.term.clone())
+                    .collect(),
+                None => vec![source.clone()],
+            };
+            (source, forms)
+        })
+        .collect()
+}
+
+// The words of all the candidates that a dictionary knows, found with one query per
+// batch rather than a lookup per candidate
+fn known_forms(
+    dict_db: &DictDb,
+    candidates: &[Vec<(String, Vec<String>)>],
+    is_japanese: bool,
+) -> rusqlite::Result<HashSet<String>> {
+    let words = candidates
+        .iter()
+        .flatten()
+        .flat_map(|(_, forms)| forms.iter().map(String::as_str))
+        .collect::<Vec<_>>();
+    dict_db.known_words(&words, is_japanese)
+}
+
+// Looks up the candidates longest first until one matches, only the ones with a known
+// form are looked up in full
+fn scan_known(
+    dict_db: &DictDb,
+    candidates: Vec<(String, Vec<String>)>,
+    known: &HashSet<String>,
+) -> Result<Option<ScanResult>> {
+    for (source, forms) in candidates {
+        if !forms.iter().any(|form| known.contains(form)) {
+            continue;
+        }
+        let results = lookup(dict_db, source.clone())?;
+        if !results.is_empty() {
+            return Ok(Some(ScanResult { source, results }));
         }
+    }
+    Ok(None)
+}
+
+fn scan_deinflector(dict_db: &DictDb) -> Result<Option<&deinflect::Deinflector>> {
+    let config = get_config()?;
+    if config.is_japanese {
+        Ok(Some(dict_db.deinflector(&config.deinflect)?))
     } else {
-        results = dict_db.lookup_word(&word, config.lookup.sort_freq, false)?;
+        Ok(None)
     }
+}
 
-    Ok(results)
+// Looks up successively shorter substrings from the character offset until one matches
+pub fn scan(dict_db: &DictDb, text: &str, offset: usize) -> Result<Option<ScanResult>> {
+    let is_japanese = get_config()?.is_japanese;
+    let candidates = scan_forms(scan_deinflector(dict_db)?, text, offset);
+    let known = known_forms(dict_db, slice::from_ref(&candidates), is_japanese)?;
+    scan_known(dict_db, candidates, &known)
+}
+
+// Scans from where a selection, which may be partial or inflected, starts in the text
+fn scan_selection(dict_db: &DictDb, text: &str, selection: &str) -> Result<Option<ScanResult>> {
+    match text.find(selection) {
+        Some(index) => scan(dict_db, text, text[..index].chars().count()),
+        None => scan(dict_db, selection, 0),
+    }
+}
+
+// The term a selection in the text stands for, the selection itself when it is one,
+// otherwise what scanning from where it starts finds as it may be partial or inflected
+pub fn selected_term(
+    dict_db: &DictDb,
+    text: &str,
+    selection: &str,
+    lookup_config: &LookupConfig,
+    is_japanese: bool,
+) -> Result<String> {
+    let selection = selection.trim();
+    if !dict_db
+        .lookup_word(selection, lookup_config, is_japanese)?
+        .is_empty()
+    {
+        return Ok(selection.to_string());
+    }
+    Ok(match scan_selection(dict_db, text, selection)? {
+        Some(scan) => scan.results[0].term.clone(),
+        None => selection.to_string(),
+    })
+}
+
+// Splits text into terms greedily, taking the longest match at each position
+// and skipping over characters that start none
+pub fn segment(dict_db: &DictDb, text: &str) -> Result<Vec<ScanResult>> {
+    let len = text.chars().count();
+    let is_japanese = get_config()?.is_japanese;
+    let deinflector = scan_deinflector(dict_db)?;
+    // what the dictionaries know is queried once for every offset
+    let mut candidates = (0..len)
+        .map(|offset| scan_forms(deinflector, text, offset))
+        .collect::<Vec<_>>();
+    let known = known_forms(dict_db, &candidates, is_japanese)?;
+
+    let mut terms = vec![];
+    let mut offset = 0;
+    while offset < len {
+        match scan_known(dict_db, mem::take(&mut candidates[offset]), &known)? {
+            Some(term) => {
+                offset += term.source.chars().count();
+                terms.push(term);
+            }
+            None => offset += 1,
+        }
+    }
+    Ok(terms)
+}
+
+// Splits lookup results into the senses of each term, keeping entries that share
+// a dictionary and sequence number together like yomichan does
+pub fn group_by_sequence(entries: &[DbDictEntry]) -> Vec<Vec<&DbDictEntry>> {
+    let mut groups: Vec<Vec<&DbDictEntry>> = vec![];
+    for entry in entries {
+        let group = groups.iter_mut().find(|group| {
+            let first = group[0];
+            entry.sequence.is_some()
+                && first.dict_id == entry.dict_id
+                && first.sequence == entry.sequence
+        });
+        match group {
+            Some(group) => group.push(entry),
+            None => groups.push(vec![entry]),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entries_query, ENTRIES_BY_DICT_FROM, ENTRIES_FROM};
+    use crate::config::{FrequencyRanking, LookupConfig};
+    use crate::deinflect::Deinflector;
+    use crate::test_utils::test_db;
+
+    #[test]
+    fn looks_up_terms_by_reading() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('上手', 'じょうず', 'skillful', 1);
+            INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('上手', 'うわて', 'upper part', 1);
+            INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('上手', 'じょうず', 'flattery', 1);
+            INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('すし', '', 'sushi', 1);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: false,
+            freq_ranking: FrequencyRanking::Min,
+        };
+
+        let groups = db.lookup_term("上手", None, &lookup_config).unwrap();
+        let readings = groups
+            .iter()
+            .map(|group| (group.reading.as_str(), group.entries.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(readings, vec![("じょうず", 2), ("うわて", 1)]);
+
+        let groups = db
+            .lookup_term("上手", Some("うわて"), &lookup_config)
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].entries[0].meaning, "upper part");
+
+        let groups = db
+            .lookup_term("すし", Some("すし"), &lookup_config)
+            .unwrap();
+        assert_eq!(groups[0].reading, "すし");
+
+        let plan = |from: &str, condition: &str| {
+            let sql = format!("EXPLAIN QUERY PLAN {}", entries_query(from, condition));
+            let mut stmt = db.conn.conn.prepare(&sql).unwrap();
+            let details = stmt
+                .query_map(&[(":fallback", &false)], |row| row.get::<_, String>(3))
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap();
+            details.join("\n")
+        };
+        let reading_plan = plan(ENTRIES_FROM, "reading = 'はし'");
+        assert!(reading_plan.contains("reading_idx"), "{}", reading_plan);
+        let term_plan = plan(ENTRIES_BY_DICT_FROM, "expression = '橋'");
+        assert!(term_plan.contains("dict_word_idx"), "{}", term_plan);
+    }
+
+    #[test]
+    fn looks_up_exact_terms() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('上手', 'じょうず', 'skillful', 1);
+            INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('上手', 'うわて', 'upper part', 1);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: false,
+            freq_ranking: FrequencyRanking::Min,
+        };
+
+        let results = super::lookup_exact(&db, "上手", "うわて", &lookup_config).unwrap();
+        let json = serde_json::to_value(&results).unwrap();
+        assert_eq!(json[0]["term"], "上手");
+        assert_eq!(json[0]["reasons"], serde_json::json!([]));
+        assert_eq!(json[0]["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(json[0]["entries"][0]["meaning"], "upper part");
+
+        let results = super::lookup_exact(&db, "上手", "かみて", &lookup_config).unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn manages_frequency_lists() {
+        let db = test_db("");
+        let lookup_config = LookupConfig {
+            sort_freq: true,
+            freq_ranking: FrequencyRanking::Min,
+        };
+
+        assert!(db.rename_frequency_list("missing", "jpdb").is_err());
+        assert!(db.set_frequency_list_enabled("missing", false).is_err());
+        assert!(db.rename_frequency_list("default", "min").is_err());
+        assert!(db
+            .check_frequency_ranking(&FrequencyRanking::List("jpdb".to_string()))
+            .is_err());
+
+        db.rename_frequency_list("default", "jpdb").unwrap();
+        db.check_frequency_ranking(&FrequencyRanking::List("jpdb".to_string()))
+            .unwrap();
+        let entries = db.lookup_word("はし", &lookup_config, true).unwrap();
+        assert_eq!(entries[0].frequencies[0].list, "jpdb");
+        assert_eq!(entries[0].rank, Some(300));
+
+        db.set_frequency_list_enabled("jpdb", false).unwrap();
+        let entries = db.lookup_word("はし", &lookup_config, true).unwrap();
+        assert!(entries.iter().all(|entry| entry.frequencies.is_empty()));
+    }
+
+    #[test]
+    fn looks_up_chinese_in_either_script() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id, traditional, simplified, pinyin)
+            VALUES ('中国', 'Zhong1 guo2', 'China', 1, '中國', '中国', 'Zhong1 guo2');",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: false,
+            freq_ranking: FrequencyRanking::Min,
+        };
+
+        for word in ["中国", "中國"] {
+            let entries = db.lookup_word(word, &lookup_config, false).unwrap();
+            assert_eq!(entries.len(), 1, "{}", word);
+            assert_eq!(entries[0].pinyin, "Zhong1 guo2");
+        }
+    }
+
+    #[test]
+    fn searches_terms_and_meanings() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('橋渡し', 'はしわたし', 'bridge building; mediation', 1);
+            INSERT INTO freq (list_id, word, reading, freq) VALUES (1, '箸', 'はし', 100);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: true,
+            freq_ranking: FrequencyRanking::Min,
+        };
+        let search = |query: &str, reverse: bool| {
+            db.search(query, reverse, 10, &lookup_config)
+                .unwrap()
+                .into_iter()
+                .map(|group| group.expression)
+                .collect::<Vec<_>>()
+        };
+
+        // the imported entries were indexed by the migration
+        // the frequency of a reading wins over the one of the word
+        assert_eq!(search("はし*", false), vec!["箸", "橋", "橋渡し"]);
+        // the limit is on terms, each keeps all of its entries
+        let groups = db.search("橋*", false, 1, &lookup_config).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].expression, "橋");
+        assert_eq!(groups[0].entries.len(), 1);
+        assert_eq!(search("*渡し", false), vec!["橋渡し"]);
+        assert_eq!(search("はし", false), vec!["箸", "橋"]);
+        assert_eq!(search("bridge", true), vec!["橋", "橋渡し"]);
+        assert_eq!(search("medi*", true), vec!["橋渡し"]);
+        assert!(search("chopsticks bridge", true).is_empty());
+    }
+
+    #[test]
+    fn keeps_selections_that_are_terms() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('橋渡し', 'はしわたし', 'mediation', 1);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: true,
+            freq_ranking: FrequencyRanking::Min,
+        };
+        // scanning from the selection would find the longer 橋渡し
+        let term = super::selected_term(&db, "橋渡しをする", " 橋 ", &lookup_config, true);
+        assert_eq!(term.unwrap(), "橋");
+    }
+
+    #[test]
+    fn finds_known_words_at_once() {
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id, traditional) VALUES ('中国', 'Zhong1 guo2', 'China', 1, '中國');",
+        );
+        let known = db
+            .known_words(&["はし", "橋", "はしわたし", "橋渡し", "はし"], true)
+            .unwrap();
+        let mut known = known.into_iter().collect::<Vec<_>>();
+        known.sort();
+        assert_eq!(known, vec!["はし", "橋"]);
+
+        let known = db.known_words(&["中國", "中", "はし"], false).unwrap();
+        assert_eq!(known.into_iter().collect::<Vec<_>>(), vec!["中國"]);
+    }
+
+    #[test]
+    fn deinflects_to_entries_without_rules() {
+        // rows from before rules were stored, or from formats that have none
+        let db = test_db(
+            "INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('食べる', 'たべる', 'to eat', 1);",
+        );
+        let lookup_config = LookupConfig {
+            sort_freq: false,
+            freq_ranking: FrequencyRanking::Min,
+        };
+        let deinflector = Deinflector::new(include_str!("../data/deinflect.json"));
+        let results =
+            super::lookup_deinflected(&db, &deinflector, "食べた".to_string(), &lookup_config)
+                .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].term, "食べる");
+        assert_eq!(results[0].entries[0].meaning, "to eat");
+    }
+
+    #[test]
+    fn scans_from_offset() {
+        assert_eq!(
+            super::scan_candidates("彼は本を読んでいる。", 4),
+            vec!["読んでいる", "読んでい", "読んで", "読ん", "読"]
+        );
+        assert_eq!(super::scan_candidates("学校、行く", 2), Vec::<String>::new());
+        assert_eq!(super::scan_candidates("我爱你", 1)[0], "爱你");
+    }
 }
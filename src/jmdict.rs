@@ -0,0 +1,402 @@
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::Reader;
+use regex::Regex;
+use std::io::BufRead;
+
+use crate::yomichan::TermEntry;
+
+#[derive(Debug, Default)]
+struct KanjiElement {
+    keb: String,
+    info: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct ReadingElement {
+    reb: String,
+    // the reading is not a true reading of the kanji, e.g. a gairaigo
+    no_kanji: bool,
+    restrictions: Vec<String>,
+    info: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Sense {
+    // the sense only applies to these kanji and readings when set
+    stagk: Vec<String>,
+    stagr: Vec<String>,
+    pos: Vec<String>,
+    tags: Vec<String>,
+    glosses: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct JmdictEntry {
+    sequence: i64,
+    kanji: Vec<KanjiElement>,
+    readings: Vec<ReadingElement>,
+    senses: Vec<Sense>,
+}
+
+// Streams the entries of a JMdict XML file, the DOCTYPE before them defines the
+// entities used for parts of speech and other tags
+pub struct JmdictReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    // (name, expansion) in definition order
+    pub entities: Vec<(String, String)>,
+    // (name, element it was used in)
+    pub categories: Vec<(String, String)>,
+    // date from the `JMdict created` comment
+    pub created: String,
+}
+
+impl<R: BufRead> JmdictReader<R> {
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = Reader::from_reader(reader);
+        reader.trim_text(true);
+        let mut jmdict = JmdictReader {
+            reader,
+            buf: vec![],
+            entities: vec![],
+            categories: vec![],
+            created: String::new(),
+        };
+
+        // everything up to the root element
+        loop {
+            match jmdict.reader.read_event(&mut jmdict.buf)? {
+                Event::DocType(doctype) => jmdict.entities = parse_entities(doctype.escaped()),
+                Event::Comment(comment) => {
+                    let comment = String::from_utf8_lossy(comment.escaped()).to_string();
+                    if let Some(created) = comment.trim().strip_prefix("JMdict created:") {
+                        jmdict.created = created.trim().to_string();
+                    }
+                }
+                Event::Start(start) if start.name() == b"JMdict" => break,
+                Event::Eof => bail!("Missing JMdict element"),
+                _ => (),
+            }
+            jmdict.buf.clear();
+        }
+        Ok(jmdict)
+    }
+
+    pub fn next_entry(&mut self) -> Result<Option<JmdictEntry>> {
+        let mut entry: Option<JmdictEntry> = None;
+        let mut element = vec![];
+        // glosses in other languages are skipped
+        let mut english = true;
+        loop {
+            self.buf.clear();
+            match self.reader.read_event(&mut self.buf)? {
+                Event::Start(start) => {
+                    element = start.name().to_vec();
+                    match (element.as_slice(), entry.as_mut()) {
+                        (b"entry", _) => entry = Some(JmdictEntry::default()),
+                        (b"k_ele", Some(entry)) => entry.kanji.push(KanjiElement::default()),
+                        (b"r_ele", Some(entry)) => entry.readings.push(ReadingElement::default()),
+                        (b"sense", Some(entry)) => entry.senses.push(Sense::default()),
+                        (b"gloss", _) => english = is_english(&start)?,
+                        _ => (),
+                    }
+                }
+                Event::Empty(empty) => {
+                    if let (b"re_nokanji", Some(entry)) = (empty.name(), entry.as_mut()) {
+                        if let Some(reading) = entry.readings.last_mut() {
+                            reading.no_kanji = true;
+                        }
+                    }
+                }
+                Event::Text(text) => {
+                    if let Some(entry) = entry.as_mut() {
+                        let name = String::from_utf8_lossy(&element).to_string();
+                        match name.as_str() {
+                            "ke_inf" | "re_inf" | "pos" | "misc" | "field" | "dial" => {
+                                let tag = entity_name(&text);
+                                if self.categories.iter().all(|(other, _)| *other != tag) {
+                                    self.categories.push((tag.clone(), name.clone()));
+                                }
+                                entry.add_tag(&name, tag);
+                            }
+                            "gloss" if !english => (),
+                            _ => {
+                                let text = text.unescape_and_decode(&self.reader)?;
+                                entry.add_text(&name, text)?;
+                            }
+                        }
+                    }
+                }
+                Event::End(end) => {
+                    element.clear();
+                    if end.name() == b"entry" {
+                        return Ok(entry);
+                    }
+                }
+                Event::Eof => return Ok(None),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl JmdictEntry {
+    fn add_tag(&mut self, element: &str, tag: String) {
+        match element {
+            "ke_inf" => push_last(self.kanji.last_mut().map(|kanji| &mut kanji.info), tag),
+            "re_inf" => push_last(
+                self.readings.last_mut().map(|reading| &mut reading.info),
+                tag,
+            ),
+            "pos" => push_last(self.senses.last_mut().map(|sense| &mut sense.pos), tag),
+            _ => push_last(self.senses.last_mut().map(|sense| &mut sense.tags), tag),
+        }
+    }
+
+    fn add_text(&mut self, element: &str, text: String) -> Result<()> {
+        match element {
+            "ent_seq" => {
+                self.sequence = text
+                    .parse()
+                    .with_context(|| format!("Invalid ent_seq {}", text))?
+            }
+            "keb" => {
+                if let Some(kanji) = self.kanji.last_mut() {
+                    kanji.keb = text;
+                }
+            }
+            "reb" => {
+                if let Some(reading) = self.readings.last_mut() {
+                    reading.reb = text;
+                }
+            }
+            "re_restr" => push_last(
+                self.readings
+                    .last_mut()
+                    .map(|reading| &mut reading.restrictions),
+                text,
+            ),
+            "stagk" => push_last(self.senses.last_mut().map(|sense| &mut sense.stagk), text),
+            "stagr" => push_last(self.senses.last_mut().map(|sense| &mut sense.stagr), text),
+            "gloss" => push_last(self.senses.last_mut().map(|sense| &mut sense.glosses), text),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // One term per sense for every kanji/reading pair the sense applies to
+    pub fn terms(&self) -> Vec<TermEntry> {
+        let mut pairs: Vec<(Option<&KanjiElement>, &ReadingElement)> = vec![];
+        for kanji in &self.kanji {
+            for reading in &self.readings {
+                let restricted =
+                    !reading.restrictions.is_empty() && !reading.restrictions.contains(&kanji.keb);
+                if !reading.no_kanji && !restricted {
+                    pairs.push((Some(kanji), reading));
+                }
+            }
+        }
+        for reading in &self.readings {
+            if self.kanji.is_empty() || reading.no_kanji {
+                pairs.push((None, reading));
+            }
+        }
+
+        let mut terms = vec![];
+        for (kanji, reading) in pairs {
+            // a sense without parts of speech has the ones of the sense before it
+            let mut pos: &[String] = &[];
+            for sense in &self.senses {
+                if !sense.pos.is_empty() {
+                    pos = &sense.pos;
+                }
+                let kanji_matches = match kanji {
+                    Some(kanji) => sense.stagk.is_empty() || sense.stagk.contains(&kanji.keb),
+                    None => sense.stagk.is_empty(),
+                };
+                let reading_matches = sense.stagr.is_empty() || sense.stagr.contains(&reading.reb);
+                if !kanji_matches || !reading_matches || sense.glosses.is_empty() {
+                    continue;
+                }
+
+                let mut rules: Vec<&str> = vec![];
+                for rule in pos.iter().filter_map(|pos| pos_rule(pos)) {
+                    if !rules.contains(&rule) {
+                        rules.push(rule);
+                    }
+                }
+                let term_tags = kanji
+                    .map(|kanji| kanji.info.as_slice())
+                    .unwrap_or_default()
+                    .iter()
+                    .chain(&reading.info)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                terms.push(TermEntry {
+                    expression: kanji.map_or(&reading.reb, |kanji| &kanji.keb).to_string(),
+                    reading: reading.reb.to_string(),
+                    definition_tags: pos
+                        .iter()
+                        .chain(&sense.tags)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    rule_identifiers: rules.join(" "),
+                    popularity: 0.0,
+                    meanings: sense.glosses.clone(),
+                    sequence: Some(self.sequence),
+                    term_tags: term_tags.join(" "),
+                    ..Default::default()
+                });
+            }
+        }
+        terms
+    }
+}
+
+fn push_last(list: Option<&mut Vec<String>>, value: String) {
+    if let Some(list) = list {
+        list.push(value);
+    }
+}
+
+fn is_english(gloss: &BytesStart) -> Result<bool> {
+    for attribute in gloss.attributes() {
+        let attribute = attribute?;
+        if attribute.key == b"xml:lang" {
+            return Ok(attribute.value.as_ref() == b"eng");
+        }
+    }
+    Ok(true)
+}
+
+// The elements holding tags only contain a single entity reference, e.g. `&v5k;`
+fn entity_name(text: &BytesText) -> String {
+    let text = String::from_utf8_lossy(text.escaped());
+    text.trim_start_matches('&')
+        .trim_end_matches(';')
+        .to_string()
+}
+
+fn parse_entities(doctype: &[u8]) -> Vec<(String, String)> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r#"<!ENTITY\s+(\S+)\s+"([^"]*)">"#).unwrap();
+    }
+    let doctype = String::from_utf8_lossy(doctype);
+    RE.captures_iter(&doctype)
+        .map(|captures| (captures[1].to_string(), captures[2].to_string()))
+        .collect()
+}
+
+// Deinflection rules of the conjugating parts of speech
+fn pos_rule(pos: &str) -> Option<&'static str> {
+    match pos {
+        "adj-i" => Some("adj-i"),
+        "vk" => Some("vk"),
+        "vz" => Some("vz"),
+        "vs" | "vs-i" | "vs-s" => Some("vs"),
+        // v5r-i and v5aru are irregular but close enough for deinflection
+        _ if pos.starts_with("v5") => Some("v5"),
+        _ if pos.starts_with("v1") => Some("v1"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JmdictReader;
+
+    const JMDICT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE JMdict [
+<!ELEMENT JMdict (entry*)>
+<!ENTITY v5k "Godan verb with 'ku' ending">
+<!ENTITY vt "transitive verb">
+<!ENTITY n "noun (common) (futsuumeishi)">
+<!ENTITY uk "word usually written using kana alone">
+<!ENTITY ateji "ateji (phonetic) reading">
+]>
+<!-- JMdict created: 2024-03-10 -->
+<JMdict>
+<entry>
+<ent_seq>1000001</ent_seq>
+<k_ele><keb>書く</keb></k_ele>
+<k_ele><keb>描く</keb><ke_inf>&ateji;</ke_inf></k_ele>
+<r_ele><reb>かく</reb></r_ele>
+<sense>
+<stagk>書く</stagk>
+<pos>&v5k;</pos>
+<pos>&vt;</pos>
+<gloss>to write</gloss>
+<gloss xml:lang="ger">schreiben</gloss>
+</sense>
+<sense>
+<stagk>描く</stagk>
+<gloss>to draw &amp; paint</gloss>
+</sense>
+</entry>
+<entry>
+<ent_seq>1000002</ent_seq>
+<k_ele><keb>煙草</keb></k_ele>
+<r_ele><reb>たばこ</reb></r_ele>
+<r_ele><reb>タバコ</reb><re_nokanji/></r_ele>
+<sense>
+<pos>&n;</pos>
+<misc>&uk;</misc>
+<gloss>tobacco</gloss>
+</sense>
+</entry>
+</JMdict>
+"#;
+
+    #[test]
+    fn parses_jmdict() {
+        let mut reader = JmdictReader::new(JMDICT.as_bytes()).unwrap();
+        assert_eq!(reader.created, "2024-03-10");
+        assert_eq!(reader.entities.len(), 5);
+        assert_eq!(reader.entities[0].0, "v5k");
+
+        let terms = reader.next_entry().unwrap().unwrap().terms();
+        let summary = terms
+            .iter()
+            .map(|term| {
+                (
+                    term.expression.as_str(),
+                    term.meanings.join("; "),
+                    term.definition_tags.as_str(),
+                    term.rule_identifiers.as_str(),
+                    term.term_tags.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("書く", "to write".to_string(), "v5k vt", "v5", ""),
+                // parts of speech carry over from the previous sense
+                (
+                    "描く",
+                    "to draw & paint".to_string(),
+                    "v5k vt",
+                    "v5",
+                    "ateji"
+                ),
+            ]
+        );
+        assert_eq!(terms[0].sequence, Some(1000001));
+
+        let terms = reader.next_entry().unwrap().unwrap().terms();
+        let pairs = terms
+            .iter()
+            .map(|term| (term.expression.as_str(), term.reading.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, vec![("煙草", "たばこ"), ("タバコ", "タバコ")]);
+        assert_eq!(terms[1].definition_tags, "n uk");
+
+        assert!(reader.next_entry().unwrap().is_none());
+        assert!(reader
+            .categories
+            .contains(&("v5k".to_string(), "pos".to_string())));
+    }
+}
@@ -1,22 +1,30 @@
 mod ace;
 mod anki;
+mod cedict;
 mod config;
 mod deinflect;
 mod dict;
+mod jmdict;
 mod media;
+mod migrations;
+mod pitch;
+mod stardict;
+#[cfg(test)]
+mod test_utils;
+mod yomichan;
 
 use ace::{get_config, package_card};
-use anki::{AnkiConnect, NoteData};
-use core::time;
+use anki::AnkiConnect;
 use once_cell::sync::OnceCell;
-use std::{fs, path::Path, thread::sleep_ms};
+use std::{fs, path::Path, thread::sleep, time::Duration};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use cli_clipboard::{ClipboardContext, ClipboardProvider};
 use config::Config;
-use dict::DictDb;
+use dict::{DictDb, LookupResult};
 use directories::BaseDirs;
+use yomichan::html_to_text;
 
 static CONFIG: OnceCell<Config> = OnceCell::new();
 
@@ -42,38 +50,161 @@ fn get_matches() -> ArgMatches<'static> {
                 .takes_value(true),
         )
         .subcommand(
-            SubCommand::with_name("add").arg(
-                Arg::with_name("with-sentence")
-                    .long("with-sentence")
-                    .short("s")
-                    .takes_value(false),
-            ),
+            SubCommand::with_name("add")
+                .arg(
+                    Arg::with_name("with-sentence")
+                        .long("with-sentence")
+                        .short("s")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("offset")
+                        .long("offset")
+                        .short("o")
+                        .help("Character offset of the word in the copied sentence")
+                        .requires("with-sentence")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             SubCommand::with_name("import")
                 .arg(Arg::with_name("name"))
-                .arg(Arg::with_name("path")),
+                .arg(Arg::with_name("path"))
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .help("Replace a dictionary that was already imported under this name")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the dictionary, jmdict and cedict take the (optionally gzipped) release file, stardict the .ifo file")
+                        .possible_values(&["yomichan", "jmdict", "cedict", "stardict"])
+                        .default_value("yomichan"),
+                )
+                .arg(
+                    Arg::with_name("defer-indexes")
+                        .long("defer-indexes")
+                        .help("Rebuild the lookup indexes after importing, faster for large dictionaries")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .arg(Arg::with_name("name"))
+                .arg(
+                    Arg::with_name("frequency")
+                        .long("frequency")
+                        .help("Delete the frequency list with this name instead")
+                        .takes_value(false),
+                ),
         )
         .subcommand(
             SubCommand::with_name("frequency")
+                .about("Manage frequency lists")
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .arg(Arg::with_name("name"))
+                        .arg(Arg::with_name("path"))
+                        .arg(
+                            Arg::with_name("corpus")
+                                .long("corpus")
+                                .help(
+                                    "Rank words by their order in the list instead of their values",
+                                )
+                                .takes_value(false),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list"))
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .arg(Arg::with_name("oldname"))
+                        .arg(Arg::with_name("newname")),
+                )
+                .subcommand(SubCommand::with_name("delete").arg(Arg::with_name("name")))
+                .subcommand(SubCommand::with_name("enable").arg(Arg::with_name("name")))
+                .subcommand(SubCommand::with_name("disable").arg(Arg::with_name("name"))),
+        )
+        .subcommand(
+            SubCommand::with_name("export-dict")
+                .about("Export an imported dictionary as a yomichan archive")
+                .arg(Arg::with_name("name"))
                 .arg(Arg::with_name("path"))
                 .arg(
-                    Arg::with_name("avg")
-                        .validator(|arg| {
-                            arg.parse::<bool>()
-                                .map(|_| ())
-                                .map_err(|_| String::from("Must pass a boolean value"))
-                        })
-                        .default_value("false"),
+                    Arg::with_name("frequency")
+                        .long("frequency")
+                        .help("Export the frequency list with this name instead")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lookup")
+                .about("Look up a word the way cards are made for it")
+                .arg(Arg::with_name("word"))
+                .arg(
+                    Arg::with_name("reading")
+                        .long("reading")
+                        .short("r")
+                        .help("Only look up the term with this reading, without deinflecting it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the results as JSON")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Extract the words of a text into a words file")
+                .arg(Arg::with_name("path"))
+                .arg(
+                    Arg::with_name("min-rank")
+                        .long("min-rank")
+                        .help("Skip words ranked more common than this")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-rank")
+                        .long("max-rank")
+                        .help("Skip words ranked rarer than this, or not ranked at all")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("known")
+                .about("List the words of the notes in Anki, one per line")
+                .arg(
+                    Arg::with_name("decks")
+                        .help("Decks to list the words of, all of them by default")
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("field")
+                        .long("field")
+                        .help("Field holding the word, the configured word field by default")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("search")
+                .about("Search the dictionaries, `*` and `?` are wildcards")
+                .arg(Arg::with_name("query"))
+                .arg(
+                    Arg::with_name("reverse")
+                        .long("reverse")
+                        .short("r")
+                        .help("Search the meanings instead, e.g. English to Japanese")
+                        .takes_value(false),
                 )
                 .arg(
-                    Arg::with_name("corpus")
-                        .validator(|arg| {
-                            arg.parse::<bool>()
-                                .map(|_| ())
-                                .map_err(|_| String::from("Must pass a boolean value"))
-                        })
-                        .default_value("false"),
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .short("l")
+                        .help("Maximum number of terms to show")
+                        .default_value("20"),
                 ),
         )
         .subcommand(
@@ -81,7 +212,15 @@ fn get_matches() -> ArgMatches<'static> {
                 .arg(Arg::with_name("oldname"))
                 .arg(Arg::with_name("newname")),
         )
-        .subcommand(SubCommand::with_name("get_dicts"))
+        .subcommand(
+            SubCommand::with_name("get_dicts").arg(
+                Arg::with_name("verbose")
+                    .long("verbose")
+                    .short("v")
+                    .help("Show the author, url and description of each dictionary")
+                    .takes_value(false),
+            ),
+        )
         .get_matches();
     matches
 }
@@ -129,21 +268,100 @@ fn has_updated_config(config_path: String, basedirs: &BaseDirs) -> Result<bool>
     Ok(updated_config)
 }
 
+fn manage_frequency_lists(dict_db: &mut DictDb, matches: &ArgMatches) -> Result<()> {
+    let name = |matches: &ArgMatches<'_>| match matches.value_of("name") {
+        Some(val) => Ok(val.to_string()),
+        None => Err(anyhow!("Must pass in a frequency list name")),
+    };
+
+    match matches.subcommand() {
+        ("import", Some(matches)) => {
+            let path = match matches.value_of("path") {
+                Some(val) => val,
+                None => bail!("Must pass in a frequency list path"),
+            };
+            let corpus = matches.is_present("corpus");
+            dict_db.update_frequency(Path::new(&path), &name(matches)?, corpus)?;
+        }
+        ("list", Some(_)) => {
+            let lists = dict_db.get_frequency_lists()?;
+            if lists.is_empty() {
+                println!("There are no frequency lists imported.");
+                return Ok(());
+            }
+            println!("{0: <10} | {1: <10} | {2: <10}", "name", "enabled", "words");
+            for list in lists {
+                println!(
+                    "{0: <10} | {1: <10} | {2: <10}",
+                    list.name, list.enabled, list.words
+                );
+            }
+        }
+        ("rename", Some(matches)) => {
+            let old = match matches.value_of("oldname") {
+                Some(val) => val,
+                None => bail!("Must pass in the old name"),
+            };
+            let new = match matches.value_of("newname") {
+                Some(val) => val,
+                None => bail!("Must pass in a new name"),
+            };
+            dict_db.rename_frequency_list(old, new)?;
+        }
+        ("delete", Some(matches)) => dict_db.delete_frequency_list(&name(matches)?)?,
+        ("enable", Some(matches)) => {
+            dict_db.set_frequency_list_enabled(&name(matches)?, true)?;
+        }
+        ("disable", Some(matches)) => {
+            dict_db.set_frequency_list_enabled(&name(matches)?, false)?;
+        }
+        _ => bail!("Missing frequency subcommand, see ace frequency --help"),
+    }
+
+    Ok(())
+}
+
+fn print_lookup(results: &[LookupResult]) {
+    if results.is_empty() {
+        println!("No results found.");
+    }
+    for result in results {
+        if result.reasons.is_empty() {
+            println!("{}", result.term);
+        } else {
+            println!("{} « {}", result.term, result.reasons.join(" « "));
+        }
+        let mut dicts: Vec<&str> = vec![];
+        for entry in &result.entries {
+            if !dicts.contains(&entry.dict.as_str()) {
+                dicts.push(&entry.dict);
+            }
+        }
+        for dict in dicts {
+            println!("  {}", dict);
+            for entry in result.entries.iter().filter(|entry| entry.dict == dict) {
+                let reading = if entry.reading.is_empty() {
+                    &entry.expression
+                } else {
+                    &entry.reading
+                };
+                match entry.rank {
+                    Some(rank) => println!("    {} [{}] #{}", entry.expression, reading, rank),
+                    None => println!("    {} [{}]", entry.expression, reading),
+                }
+                println!("      {}", html_to_text(&entry.meaning));
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let mut dict_db = DictDb::new()?;
     let matches = get_matches();
 
     if let Some(matches) = matches.subcommand_matches("frequency") {
-        let path = match matches.value_of("path") {
-            Some(val) => val,
-            None => bail!("Must pass in a frequency list path"),
-        };
-        let avg = matches.value_of("avg").unwrap().parse::<bool>().unwrap();
-        let corpus = matches.value_of("corpus").unwrap().parse::<bool>().unwrap();
-        dict_db
-            .update_frequency(Path::new(&path), avg, corpus)
-            .unwrap();
+        manage_frequency_lists(&mut dict_db, matches)?;
         return Ok(());
     }
 
@@ -156,7 +374,55 @@ async fn main() -> Result<()> {
             Some(val) => val,
             None => bail!("Must pass in a dictionary path"),
         };
-        dict_db.load_yomichan_dict(Path::new(&path), name.to_string())?;
+        let replace = matches.is_present("replace");
+        let defer_indexes = matches.is_present("defer-indexes");
+        match matches.value_of("format") {
+            Some("jmdict") => {
+                dict_db.load_jmdict(Path::new(&path), name.to_string(), replace, defer_indexes)?
+            }
+            Some("cedict") => {
+                dict_db.load_cedict(Path::new(&path), name.to_string(), replace, defer_indexes)?
+            }
+            Some("stardict") => {
+                dict_db.load_stardict(Path::new(&path), name.to_string(), replace, defer_indexes)?
+            }
+            _ => dict_db.load_yomichan_dict(
+                Path::new(&path),
+                name.to_string(),
+                replace,
+                defer_indexes,
+            )?,
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("delete") {
+        let name = match matches.value_of("name") {
+            Some(val) => val,
+            None => bail!("Must pass in a name"),
+        };
+        if matches.is_present("frequency") {
+            dict_db.delete_frequency_list(name)?;
+        } else {
+            dict_db.delete_dict(name)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("export-dict") {
+        let name = match matches.value_of("name") {
+            Some(val) => val,
+            None => bail!("Must pass in a name"),
+        };
+        let path = match matches.value_of("path") {
+            Some(val) => val,
+            None => bail!("Must pass in an output path"),
+        };
+        if matches.is_present("frequency") {
+            dict_db.export_frequency_list(name, Path::new(&path))?;
+        } else {
+            dict_db.export_dict(name, Path::new(&path))?;
+        }
         return Ok(());
     }
 
@@ -165,6 +431,7 @@ async fn main() -> Result<()> {
     let config = Config::from_path(&config_path)?;
     CONFIG.set(config).unwrap();
     let config = get_config()?;
+    dict_db.check_frequency_ranking(&config.lookup.freq_ranking)?;
 
     let updated_config = has_updated_config(config_path, &basedirs)?;
 
@@ -186,23 +453,36 @@ async fn main() -> Result<()> {
 
         let mut sentence = String::new();
         let mut word = sentence.clone();
-        if matches.is_present("with-sentence") {
+        if let Some(offset) = matches.value_of("offset") {
+            sentence = ctx.get_contents()?;
+            let offset = offset.parse()?;
+            if offset >= sentence.chars().count() {
+                bail!("Offset is past the end of the sentence");
+            }
+            word = match dict::scan(&dict_db, &sentence, offset)? {
+                Some(scan) => scan.results[0].term.clone(),
+                None => bail!("No word found at offset {}", offset),
+            };
+        } else if matches.is_present("with-sentence") {
             sentence = ctx.get_contents()?;
 
             let mut elapsed_ms = 0;
             let wait_time_ms = 50; // ms
             let max_time = 5000;
 
-            notifica::notify(
+            // the word can still be copied without the reminder
+            if let Err(err) = notifica::notify(
                 "Vocab Card",
                 &format!(
                     "Copy a word to the clipboard within {} seconds",
                     max_time / 1000
                 ),
-            );
+            ) {
+                eprintln!("Failed to show notification: {}", err);
+            }
 
             while elapsed_ms <= max_time {
-                sleep_ms(wait_time_ms);
+                sleep(Duration::from_millis(wait_time_ms));
                 elapsed_ms += wait_time_ms;
                 word = ctx.get_contents()?;
                 if !word.is_empty() && word != sentence {
@@ -213,6 +493,13 @@ async fn main() -> Result<()> {
             if word.is_empty() || word == sentence {
                 bail!("No word provided");
             }
+            word = dict::selected_term(
+                &dict_db,
+                &sentence,
+                &word,
+                &config.lookup,
+                config.is_japanese,
+            )?;
         } else {
             word = ctx.get_contents()?;
         }
@@ -224,6 +511,90 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(matches) = matches.subcommand_matches("lookup") {
+        let word = match matches.value_of("word") {
+            Some(val) => val,
+            None => bail!("Must pass in a word"),
+        };
+        let results = match matches.value_of("reading") {
+            Some(reading) => dict::lookup_exact(&dict_db, word, reading, &config.lookup)?,
+            None => dict::lookup(&dict_db, word.to_string())?,
+        };
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&results)?);
+        } else {
+            print_lookup(&results);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("known") {
+        let anki_connect = AnkiConnect {
+            port: config.ankiconnect.port,
+            address: config.ankiconnect.address.clone(),
+        };
+        anki_connect.status().await?;
+        let decks = matches
+            .values_of("decks")
+            .map(|decks| decks.map(str::to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let field = matches
+            .value_of("field")
+            .unwrap_or(&config.anki.word_field);
+        let mut words = anki_connect
+            .known_words(&decks, field)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
+        words.sort();
+        for word in words {
+            println!("{}", word);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("scan") {
+        let path = match matches.value_of("path") {
+            Some(val) => val,
+            None => bail!("Must pass in a path"),
+        };
+        let text = fs::read_to_string(path)?;
+        let min_rank = matches.value_of("min-rank").map(str::parse).transpose()?;
+        let max_rank = matches.value_of("max-rank").map(str::parse).transpose()?;
+        for (word, sentence) in ace::scan_text(&dict_db, &text, min_rank, max_rank)? {
+            println!("{} {}", word, sentence);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("search") {
+        let query = match matches.value_of("query") {
+            Some(val) => val,
+            None => bail!("Must pass in a search query"),
+        };
+        let limit = matches.value_of("limit").unwrap_or_default().parse()?;
+        let groups = dict_db.search(
+            query,
+            matches.is_present("reverse"),
+            limit,
+            &config.lookup,
+        )?;
+        if groups.is_empty() {
+            println!("No results found.");
+        }
+        for group in groups {
+            let rank = group.entries.iter().filter_map(|entry| entry.rank).min();
+            match rank {
+                Some(rank) => println!("{} [{}] #{}", group.expression, group.reading, rank),
+                None => println!("{} [{}]", group.expression, group.reading),
+            }
+            for entry in &group.entries {
+                println!("    ({}) {}", entry.dict, html_to_text(&entry.meaning));
+            }
+        }
+        return Ok(());
+    }
+
     if let Some(matches) = matches.subcommand_matches("rename") {
         let old = match matches.value_of("oldname") {
             Some(val) => val,
@@ -237,21 +608,33 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    if matches.subcommand_matches("get_dicts").is_some() {
+    if let Some(matches) = matches.subcommand_matches("get_dicts") {
         let dicts = dict_db.get_all_dicts()?;
         if dicts.is_empty() {
             println!("There are no dictionaries imported.");
             return Ok(());
         }
         println!(
-            "{0: <10} | {1: <10} | {2: <10} | {3: <10}",
-            "title", "priority", "fallback", "enabled"
+            "{0: <10} | {1: <10} | {2: <10} | {3: <10} | {4: <10}",
+            "title", "revision", "priority", "fallback", "enabled"
         );
         for dict in dicts {
             println!(
-                "{0: <10} | {1: <10} | {2: <10} | {3: <10}",
-                dict.title, dict.priority, dict.fallback, dict.enabled
+                "{0: <10} | {1: <10} | {2: <10} | {3: <10} | {4: <10}",
+                dict.title, dict.revision, dict.priority, dict.fallback, dict.enabled
             );
+            if matches.is_present("verbose") {
+                for (label, value) in [
+                    ("author", &dict.author),
+                    ("url", &dict.url),
+                    ("description", &dict.description),
+                ] {
+                    if !value.is_empty() {
+                        println!("    {}: {}", label, value);
+                    }
+                }
+                println!("    sequenced: {}", dict.sequenced);
+            }
         }
         return Ok(());
     }
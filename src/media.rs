@@ -4,7 +4,7 @@ use rand::thread_rng;
 use regex::Regex;
 use scraper::{Html, Selector};
 use serde_json::Value;
-use std::{collections::HashMap, io::Cursor, path::Path};
+use std::collections::HashMap;
 use uuid::Uuid;
 
 use crate::anki::Media;
@@ -112,30 +112,6 @@ pub async fn get_sent(word: &str, is_japanese: bool) -> Result<String> {
     }
 }
 
-async fn download_file(url: &str, output_path: &Path, extension: Option<&str>) -> Result<()> {
-    let response = reqwest::get(url).await?;
-    let content_type = response.headers().get("Content-Type");
-
-    let mut full_path = output_path.to_path_buf();
-
-    if let Some(ext) = extension {
-        full_path.set_extension(ext);
-    } else {
-        // probe extension from content-type header
-        if let Some(ctype) = content_type {
-            let ext = ctype.to_str().unwrap().split('/').collect::<Vec<&str>>()[1];
-            full_path.set_extension(ext);
-        }
-    }
-
-    let bytes = response.bytes().await?;
-    let mut file = std::fs::File::create(full_path)?;
-    let mut content = Cursor::new(bytes);
-    std::io::copy(&mut content, &mut file)?;
-
-    Ok(())
-}
-
 pub async fn fetch_audio_server(word: &str, custom_audio_server: &str) -> Result<Media> {
     let url = custom_audio_server.replacen("{}", word, 1);
     reqwest::get(&url).await?.error_for_status()?;
@@ -213,7 +189,7 @@ pub async fn google_img(word: String, is_japanese: bool) -> Result<Media> {
     let max_offset = if urls.len() < 10 { urls.len() } else { 10 };
     let mut shuffled = urls[..max_offset].to_vec();
     shuffled.shuffle(&mut thread_rng());
-    let url = shuffled.get(0).ok_or("Could not find image").unwrap();
+    let url = shuffled.first().ok_or("Could not find image").unwrap();
     let filename = with_uuid(word.clone());
     Ok(Media {
         url: url.to_string(),
@@ -0,0 +1,275 @@
+use rusqlite::Connection;
+
+// Each step upgrades dict.db by one version, which is kept in PRAGMA user_version.
+// Databases created before migrations existed are at version 0 but already hold the
+// tables of the first step, hence the IF NOT EXISTS there. Never edit a released step,
+// append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE IF NOT EXISTS dicts (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          title           TEXT NOT NULL,
+          priority        INTEGER DEFAULT 0,
+          fallback        INTEGER DEFAULT 0,
+          enabled         INTEGER DEFAULT 0
+    );
+
+    CREATE TABLE IF NOT EXISTS entries (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          expression      TEXT NOT NULL,
+          reading         TEXT,
+          meaning         TEXT NOT NULL,
+          dict_id         INTEGER NOT NULL,
+          frequency       INTEGER DEFAULT 0,
+          FOREIGN KEY(dict_id) REFERENCES dicts(id)
+    );
+
+    CREATE TABLE IF NOT EXISTS freq (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          word            TEXT NOT NULL UNIQUE,
+          freq            INTEGER NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS word_idx ON entries(expression);",
+    // 2: term metadata and tag banks
+    "ALTER TABLE entries ADD COLUMN definition_tags TEXT DEFAULT '';
+    ALTER TABLE entries ADD COLUMN term_tags TEXT DEFAULT '';
+    ALTER TABLE entries ADD COLUMN rules TEXT DEFAULT '';
+    ALTER TABLE entries ADD COLUMN popularity INTEGER DEFAULT 0;
+    ALTER TABLE entries ADD COLUMN sequence INTEGER;
+
+    CREATE TABLE tags (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          name            TEXT NOT NULL,
+          category        TEXT DEFAULT '',
+          sort_order      INTEGER DEFAULT 0,
+          notes           TEXT DEFAULT '',
+          score           INTEGER DEFAULT 0,
+          dict_id         INTEGER NOT NULL,
+          FOREIGN KEY(dict_id) REFERENCES dicts(id)
+    );
+
+    CREATE INDEX tag_idx ON tags(dict_id, name);",
+    // 3: kanji banks
+    "CREATE TABLE kanji (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          character       TEXT NOT NULL,
+          onyomi          TEXT DEFAULT '',
+          kunyomi         TEXT DEFAULT '',
+          tags            TEXT DEFAULT '',
+          meanings        TEXT DEFAULT '',
+          stats           TEXT DEFAULT '{}',
+          dict_id         INTEGER NOT NULL,
+          FOREIGN KEY(dict_id) REFERENCES dicts(id)
+    );
+
+    CREATE INDEX kanji_idx ON kanji(character);",
+    // 4: pitch accents
+    "CREATE TABLE pitch (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          expression      TEXT NOT NULL,
+          reading         TEXT NOT NULL,
+          position        INTEGER NOT NULL,
+          tags            TEXT DEFAULT '',
+          dict_id         INTEGER NOT NULL,
+          FOREIGN KEY(dict_id) REFERENCES dicts(id)
+    );
+
+    CREATE INDEX pitch_idx ON pitch(expression, reading);",
+    // 5: named frequency lists with reading specific frequencies,
+    // the old merged frequencies are kept as a list called "default"
+    "CREATE TABLE freq_lists (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          name            TEXT NOT NULL UNIQUE,
+          enabled         INTEGER DEFAULT 1
+    );
+
+    ALTER TABLE freq RENAME TO old_freq;
+
+    CREATE TABLE freq (
+          id              INTEGER PRIMARY KEY AUTOINCREMENT,
+          list_id         INTEGER NOT NULL,
+          word            TEXT NOT NULL,
+          reading         TEXT NOT NULL DEFAULT '',
+          freq            INTEGER NOT NULL,
+          display         TEXT,
+          UNIQUE(list_id, word, reading),
+          FOREIGN KEY(list_id) REFERENCES freq_lists(id)
+    );
+
+    INSERT INTO freq_lists (name) SELECT 'default' WHERE EXISTS (SELECT 1 FROM old_freq);
+    INSERT INTO freq (list_id, word, freq)
+          SELECT (SELECT id FROM freq_lists WHERE name = 'default'), word, freq FROM old_freq;
+    DROP TABLE old_freq;
+
+    CREATE INDEX freq_word_idx ON freq(word, reading);",
+    // 6: dictionary metadata from index.json
+    "ALTER TABLE dicts ADD COLUMN revision TEXT DEFAULT '';
+    ALTER TABLE dicts ADD COLUMN author TEXT DEFAULT '';
+    ALTER TABLE dicts ADD COLUMN url TEXT DEFAULT '';
+    ALTER TABLE dicts ADD COLUMN description TEXT DEFAULT '';
+    ALTER TABLE dicts ADD COLUMN sequenced INTEGER DEFAULT 0;",
+    // 7: indexes for kana lookups and per dictionary term lookups
+    "CREATE INDEX reading_idx ON entries(reading);
+    CREATE INDEX dict_word_idx ON entries(dict_id, expression);",
+    // 8: chinese scripts and pinyin from CC-CEDICT
+    "ALTER TABLE entries ADD COLUMN traditional TEXT DEFAULT '';
+    ALTER TABLE entries ADD COLUMN simplified TEXT DEFAULT '';
+    ALTER TABLE entries ADD COLUMN pinyin TEXT DEFAULT '';
+
+    CREATE INDEX traditional_idx ON entries(traditional);",
+    // 9: full text search over entries, kept in sync by triggers
+    "CREATE VIRTUAL TABLE entries_fts USING fts5(
+        expression, reading, meaning,
+        content = 'entries', content_rowid = 'id',
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+
+    CREATE TRIGGER entries_ai AFTER INSERT ON entries BEGIN
+        INSERT INTO entries_fts (rowid, expression, reading, meaning)
+            VALUES (new.id, new.expression, new.reading, new.meaning);
+    END;
+    CREATE TRIGGER entries_ad AFTER DELETE ON entries BEGIN
+        INSERT INTO entries_fts (entries_fts, rowid, expression, reading, meaning)
+            VALUES ('delete', old.id, old.expression, old.reading, old.meaning);
+    END;
+    CREATE TRIGGER entries_au AFTER UPDATE ON entries BEGIN
+        INSERT INTO entries_fts (entries_fts, rowid, expression, reading, meaning)
+            VALUES ('delete', old.id, old.expression, old.reading, old.meaning);
+        INSERT INTO entries_fts (rowid, expression, reading, meaning)
+            VALUES (new.id, new.expression, new.reading, new.meaning);
+    END;
+
+    INSERT INTO entries_fts (entries_fts) VALUES ('rebuild');",
+    // 10: glosses imported from one term bank row share a sense id
+    "ALTER TABLE entries ADD COLUMN sense INTEGER;",
+    // 11: what exports need to write dictionaries back as they were imported, the original
+    // glossary JSON and the frequencies that came with a dictionary
+    "ALTER TABLE entries ADD COLUMN glossary TEXT;
+    ALTER TABLE freq_lists ADD COLUMN mode TEXT DEFAULT 'rank-based';
+    ALTER TABLE freq_lists ADD COLUMN dict_id INTEGER REFERENCES dicts(id);",
+];
+
+// Keeps the full text index up to date on every insert, see migration 9
+const ENTRY_FTS_TRIGGER: &str = "CREATE TRIGGER IF NOT EXISTS entries_ai AFTER INSERT ON entries BEGIN
+    INSERT INTO entries_fts (rowid, expression, reading, meaning)
+        VALUES (new.id, new.expression, new.reading, new.meaning);
+END;";
+
+// Indexes on entries, bulk imports can drop them and build them once at the end
+const ENTRY_INDEXES: &[(&str, &str)] = &[
+    ("word_idx", "entries(expression)"),
+    ("reading_idx", "entries(reading)"),
+    ("dict_word_idx", "entries(dict_id, expression)"),
+    ("traditional_idx", "entries(traditional)"),
+];
+
+pub fn drop_entry_indexes(conn: &Connection) -> rusqlite::Result<()> {
+    for (name, _) in ENTRY_INDEXES {
+        conn.execute_batch(&format!("DROP INDEX IF EXISTS {}", name))?;
+    }
+    // the full text index is rebuilt from scratch afterwards instead
+    conn.execute_batch("DROP TRIGGER IF EXISTS entries_ai")?;
+    Ok(())
+}
+
+pub fn create_entry_indexes(conn: &Connection) -> rusqlite::Result<()> {
+    for (name, columns) in ENTRY_INDEXES {
+        conn.execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {}",
+            name, columns
+        ))?;
+    }
+    if !trigger_exists(conn, "entries_ai")? {
+        conn.execute_batch(ENTRY_FTS_TRIGGER)?;
+        conn.execute_batch("INSERT INTO entries_fts (entries_fts) VALUES ('rebuild')")?;
+    }
+    Ok(())
+}
+
+fn trigger_exists(conn: &Connection, name: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND name = ?1)",
+        [name],
+        |row| row.get(0),
+    )
+}
+
+pub fn schema_version(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Brings the database up to date one step at a time, each step in its own transaction
+pub fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version = schema_version(conn)?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate, schema_version, MIGRATIONS};
+    use crate::config::{FrequencyRanking, LookupConfig};
+    use crate::dict::DictDb;
+    use crate::test_utils::v0_database;
+    use rusqlite::Connection;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap();
+        let mut columns = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .unwrap();
+        columns.sort();
+        columns
+    }
+
+    #[test]
+    fn migrates_v0_database() {
+        let mut conn = v0_database();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        // migrating an up to date database does nothing
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        let mut fresh = Connection::open_in_memory().unwrap();
+        migrate(&mut fresh).unwrap();
+        for table in [
+            "dicts",
+            "entries",
+            "tags",
+            "kanji",
+            "pitch",
+            "freq_lists",
+            "freq",
+        ] {
+            assert_eq!(columns(&conn, table), columns(&fresh, table), "{}", table);
+        }
+
+        let db = DictDb::open(conn).unwrap();
+        let lookup_config = LookupConfig {
+            sort_freq: true,
+            freq_ranking: FrequencyRanking::Min,
+        };
+        let entries = db.lookup_word("はし", &lookup_config, true).unwrap();
+        let words = entries
+            .iter()
+            .map(|entry| entry.expression.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(words, vec!["橋", "箸"]);
+        assert_eq!(entries[0].frequencies[0].list, "default");
+        assert_eq!(entries[0].rank, Some(300));
+        assert_eq!(entries[0].definition_tags, "");
+    }
+}
@@ -0,0 +1,118 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::yomichan::escape_html;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PitchDisplay {
+    Number,
+    Graph,
+    #[default]
+    Both,
+}
+
+const SMALL_KANA: &str = "ゃゅょぁぃぅぇぉゎャュョァィゥェォヮ";
+
+// Small kana belong to the preceding mora, e.g. きょう -> [きょ, う]
+pub fn split_morae(reading: &str) -> Vec<String> {
+    let mut morae: Vec<String> = vec![];
+    for c in reading.chars() {
+        match morae.last_mut() {
+            Some(mora) if SMALL_KANA.contains(c) => mora.push(c),
+            _ => morae.push(c.to_string()),
+        }
+    }
+    morae
+}
+
+// `position` is the mora after which the pitch drops, 0 meaning it never does (heiban)
+pub fn is_high(position: usize, mora: usize) -> bool {
+    match position {
+        0 => mora > 0,
+        1 => mora == 0,
+        _ => mora > 0 && mora < position,
+    }
+}
+
+pub fn render_number(position: usize) -> String {
+    format!("[{}]", position)
+}
+
+// Yomichan style graph: one dot per mora plus a hollow one for the following particle
+pub fn render_graph(reading: &str, position: usize) -> String {
+    let morae = split_morae(reading);
+    let step = 35;
+    let width = step * (morae.len() + 1);
+    let y = |high: bool| if high { 5 } else { 30 };
+
+    let mut points = vec![];
+    let mut shapes = String::new();
+    for index in 0..=morae.len() {
+        let x = step * index + step / 2;
+        let high = is_high(position, index);
+        points.push(format!("{},{}", x, y(high)));
+        if index < morae.len() {
+            shapes += &format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"4\" fill=\"currentColor\"/>",
+                x,
+                y(high)
+            );
+            shapes += &format!(
+                "<text x=\"{}\" y=\"55\" text-anchor=\"middle\" font-size=\"20\" fill=\"currentColor\">{}</text>",
+                x,
+                escape_html(&morae[index])
+            );
+        } else {
+            shapes += &format!(
+                "<circle cx=\"{}\" cy=\"{}\" r=\"4\" fill=\"none\" stroke=\"currentColor\" stroke-width=\"2\"/>",
+                x,
+                y(high)
+            );
+        }
+    }
+
+    format!(
+        "<svg class=\"pitch\" xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} 60\" width=\"{0}\" height=\"60\">\
+         <polyline points=\"{1}\" fill=\"none\" stroke=\"currentColor\" stroke-width=\"2\"/>{2}</svg>",
+        width,
+        points.join(" "),
+        shapes
+    )
+}
+
+pub fn render_pitch(reading: &str, position: usize, display: PitchDisplay) -> String {
+    match display {
+        PitchDisplay::Number => render_number(position),
+        PitchDisplay::Graph => render_graph(reading, position),
+        PitchDisplay::Both => format!(
+            "{} {}",
+            render_number(position),
+            render_graph(reading, position)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_high, split_morae};
+
+    #[test]
+    fn splits_morae() {
+        assert_eq!(split_morae("きょうと"), vec!["きょ", "う", "と"]);
+        assert_eq!(split_morae("がっこう"), vec!["が", "っ", "こ", "う"]);
+    }
+
+    #[test]
+    fn pitch_patterns() {
+        let pattern = |position| {
+            (0..4)
+                .map(|mora| is_high(position, mora))
+                .collect::<Vec<_>>()
+        };
+        // heiban, atamadaka, nakadaka and odaka for a 3 mora word + particle
+        assert_eq!(pattern(0), vec![false, true, true, true]);
+        assert_eq!(pattern(1), vec![true, false, false, false]);
+        assert_eq!(pattern(2), vec![false, true, false, false]);
+        assert_eq!(pattern(3), vec![false, true, true, false]);
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::yomichan::{escape_html, TermEntry};
+
+// The .ifo file, `key=value` lines after a magic first line
+#[derive(Debug, Default)]
+pub struct StarDictInfo {
+    pub bookname: String,
+    pub version: String,
+    pub author: String,
+    pub description: String,
+    pub website: String,
+    pub date: String,
+    // when set, every article has these fields in this order without type markers
+    pub sametypesequence: Option<String>,
+    pub idxoffsetbits: u8,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IdxEntry {
+    pub word: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+pub struct StarDict {
+    pub info: StarDictInfo,
+    pub entries: Vec<IdxEntry>,
+    // (synonym, index into entries)
+    pub synonyms: Vec<(String, usize)>,
+    dict_path: PathBuf,
+}
+
+pub fn parse_ifo(ifo: &str) -> Result<StarDictInfo> {
+    let mut lines = ifo.lines();
+    if lines.next().map(str::trim) != Some("StarDict's dict ifo file") {
+        bail!("Not a StarDict .ifo file");
+    }
+    let mut info = StarDictInfo {
+        idxoffsetbits: 32,
+        ..Default::default()
+    };
+    for line in lines {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim().to_string()),
+            None => continue,
+        };
+        match key {
+            "bookname" => info.bookname = value,
+            "version" => info.version = value,
+            "author" => info.author = value,
+            "description" => info.description = value,
+            "website" => info.website = value,
+            "date" => info.date = value,
+            "sametypesequence" => info.sametypesequence = Some(value),
+            "idxoffsetbits" => info.idxoffsetbits = value.parse()?,
+            _ => (),
+        }
+    }
+    Ok(info)
+}
+
+// NUL terminated word followed by a big endian offset and size
+pub fn parse_idx(mut data: &[u8], offset_bits: u8) -> Result<Vec<IdxEntry>> {
+    let offset_len = if offset_bits == 64 { 8 } else { 4 };
+    let mut entries = vec![];
+    while !data.is_empty() {
+        let word = read_string(&mut data)?;
+        let offset = read_number(&mut data, offset_len)?;
+        let size = read_number(&mut data, 4)?;
+        entries.push(IdxEntry { word, offset, size });
+    }
+    Ok(entries)
+}
+
+// NUL terminated synonym followed by the big endian index of its idx entry
+pub fn parse_syn(mut data: &[u8]) -> Result<Vec<(String, usize)>> {
+    let mut synonyms = vec![];
+    while !data.is_empty() {
+        let word = read_string(&mut data)?;
+        let index = read_number(&mut data, 4)? as usize;
+        synonyms.push((word, index));
+    }
+    Ok(synonyms)
+}
+
+fn read_string(data: &mut &[u8]) -> Result<String> {
+    let end = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow!("Missing string terminator"))?;
+    let string = String::from_utf8_lossy(&data[..end]).to_string();
+    *data = &data[end + 1..];
+    Ok(string)
+}
+
+fn read_number(data: &mut &[u8], len: usize) -> Result<u64> {
+    if data.len() < len {
+        bail!("Unexpected end of data");
+    }
+    let number = data[..len]
+        .iter()
+        .fold(0, |number, &b| (number << 8) | b as u64);
+    *data = &data[len..];
+    Ok(number)
+}
+
+// Turns the fields of an article into the html of its meaning, with the phonetic
+// fields returned separately as its reading
+pub fn parse_article(mut data: &[u8], sametypesequence: Option<&str>) -> Result<(String, String)> {
+    let mut fields: Vec<(char, Vec<u8>)> = vec![];
+    match sametypesequence {
+        Some(types) => {
+            let types = types.chars().collect::<Vec<_>>();
+            for (i, &field_type) in types.iter().enumerate() {
+                // the last field takes up the rest of the article
+                let field = if i + 1 == types.len() {
+                    let field = data.to_vec();
+                    data = &[];
+                    field
+                } else {
+                    read_field(&mut data, field_type)?
+                };
+                fields.push((field_type, field));
+            }
+        }
+        None => {
+            while let Some((&field_type, rest)) = data.split_first() {
+                data = rest;
+                let field_type = field_type as char;
+                fields.push((field_type, read_field(&mut data, field_type)?));
+            }
+        }
+    }
+
+    let mut reading = vec![];
+    let mut meaning = vec![];
+    for (field_type, field) in fields {
+        let text = String::from_utf8_lossy(&field);
+        let text = text.trim_end_matches('\0').trim();
+        match field_type {
+            // phonetics and kana
+            't' | 'y' => reading.push(text.to_string()),
+            // plain text
+            'm' | 'l' => meaning.push(escape_html(text).replace('\n', "<br>")),
+            // pango markup, html and xdxf all render well enough as html
+            'g' | 'h' | 'x' => meaning.push(text.to_string()),
+            // sounds, pictures and other resources are not supported
+            _ => (),
+        }
+    }
+    Ok((reading.join(" "), meaning.join("<br>")))
+}
+
+// Lowercase types are NUL terminated text, uppercase ones binary data prefixed by their size
+fn read_field(data: &mut &[u8], field_type: char) -> Result<Vec<u8>> {
+    if field_type.is_ascii_lowercase() {
+        Ok(read_string(data)?.into_bytes())
+    } else {
+        let size = read_number(data, 4)? as usize;
+        if data.len() < size {
+            bail!("Unexpected end of article");
+        }
+        let field = data[..size].to_vec();
+        *data = &data[size..];
+        Ok(field)
+    }
+}
+
+// The .dz and .gz variants are dictzip and gzip compressed
+fn find_file(base: &Path, extension: &str) -> Option<PathBuf> {
+    [
+        extension.to_string(),
+        format!("{}.dz", extension),
+        format!("{}.gz", extension),
+    ]
+    .iter()
+    .map(|extension| base.with_extension(extension))
+    .find(|path| path.exists())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    open_file(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn open_file(path: &Path) -> Result<Box<dyn Read>> {
+    let file = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("dz") | Some("gz") => Ok(Box::new(MultiGzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+impl StarDict {
+    // Takes the .ifo file, or a directory containing one
+    pub fn open(path: &Path) -> Result<Self> {
+        let ifo_path = if path.is_dir() {
+            fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .find(|path| path.extension().is_some_and(|ext| ext == "ifo"))
+                .ok_or_else(|| anyhow!("No .ifo file in {}", path.display()))?
+        } else {
+            path.to_path_buf()
+        };
+        let info = parse_ifo(&fs::read_to_string(&ifo_path)?)?;
+
+        let idx_path = find_file(&ifo_path, "idx").context("Missing .idx file")?;
+        let entries = parse_idx(&read_file(&idx_path)?, info.idxoffsetbits)?;
+        let synonyms = match find_file(&ifo_path, "syn") {
+            Some(syn_path) => parse_syn(&read_file(&syn_path)?)?,
+            None => vec![],
+        };
+        let dict_path = find_file(&ifo_path, "dict").context("Missing .dict file")?;
+
+        Ok(StarDict {
+            info,
+            entries,
+            synonyms,
+            dict_path,
+        })
+    }
+
+    // Reads the articles in the order they are stored in, so a compressed .dict is
+    // decompressed once as a stream, giving one term per headword and synonym
+    pub fn terms(&self) -> Result<impl Iterator<Item = Result<TermEntry>> + '_> {
+        let mut order = (0..self.entries.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| (self.entries[index].offset, self.entries[index].size));
+
+        let mut synonyms: Vec<Vec<&str>> = vec![vec![]; self.entries.len()];
+        for (synonym, index) in &self.synonyms {
+            if let Some(synonyms) = synonyms.get_mut(*index) {
+                synonyms.push(synonym);
+            }
+        }
+
+        let mut dict = open_file(&self.dict_path)?;
+        let mut position = 0;
+        // articles can be shared by several headwords
+        let mut last: Option<(u64, Vec<u8>)> = None;
+        let articles = order.into_iter().map(move |index| -> Result<Vec<TermEntry>> {
+            let entry = &self.entries[index];
+            let article = match &last {
+                Some((offset, article))
+                    if entry.offset >= *offset
+                        && entry.offset + entry.size <= offset + article.len() as u64 =>
+                {
+                    let start = (entry.offset - offset) as usize;
+                    article[start..start + entry.size as usize].to_vec()
+                }
+                _ => {
+                    if entry.offset < position {
+                        bail!("Overlapping articles for {}", entry.word);
+                    }
+                    io::copy(
+                        &mut dict.by_ref().take(entry.offset - position),
+                        &mut io::sink(),
+                    )?;
+                    let mut article = vec![0; entry.size as usize];
+                    dict.read_exact(&mut article).with_context(|| {
+                        format!("Failed to read the article for {}", entry.word)
+                    })?;
+                    position = entry.offset + entry.size;
+                    last = Some((entry.offset, article.clone()));
+                    article
+                }
+            };
+
+            let (reading, meaning) =
+                parse_article(&article, self.info.sametypesequence.as_deref())?;
+            if meaning.is_empty() {
+                return Ok(vec![]);
+            }
+            let sequence = Some(index.try_into()?);
+            let headwords =
+                std::iter::once(entry.word.as_str()).chain(synonyms[index].iter().copied());
+            Ok(headwords
+                .map(|headword| TermEntry {
+                    expression: headword.to_string(),
+                    reading: reading.clone(),
+                    meanings: vec![meaning.clone()],
+                    sequence,
+                    ..Default::default()
+                })
+                .collect())
+        });
+        Ok(articles.flat_map(|terms| match terms {
+            Ok(terms) => terms.into_iter().map(Ok).collect(),
+            Err(err) => vec![Err(err)],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_article, parse_idx, parse_ifo, parse_syn, IdxEntry};
+
+    #[test]
+    fn parses_stardict_files() {
+        let info = parse_ifo(
+            "StarDict's dict ifo file\nversion=3.0.0\nbookname=Test\nsametypesequence=tm\nidxoffsetbits=64\n",
+        )
+        .unwrap();
+        assert_eq!(info.bookname, "Test");
+        assert_eq!(info.sametypesequence.as_deref(), Some("tm"));
+        assert_eq!(info.idxoffsetbits, 64);
+        assert!(parse_ifo("bookname=Test").is_err());
+
+        let idx = b"cat\0\0\0\0\0\0\0\0\x05\0\0\0\x0Adog\0\0\0\0\0\0\0\0\x0F\0\0\0\x03";
+        assert_eq!(
+            parse_idx(idx, 64).unwrap(),
+            vec![
+                IdxEntry {
+                    word: "cat".to_string(),
+                    offset: 5,
+                    size: 10
+                },
+                IdxEntry {
+                    word: "dog".to_string(),
+                    offset: 15,
+                    size: 3
+                },
+            ]
+        );
+        assert!(parse_idx(b"cat\0\0\0", 32).is_err());
+        assert_eq!(
+            parse_syn(b"kitty\0\0\0\0\0").unwrap(),
+            vec![("kitty".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn parses_articles() {
+        assert_eq!(
+            parse_article(b"k\xC3\xA6t\0a <small> animal\nmeow", Some("tm")).unwrap(),
+            (
+                "kæt".to_string(),
+                "a &lt;small&gt; animal<br>meow".to_string()
+            )
+        );
+        // without sametypesequence every field is marked with its type
+        assert_eq!(
+            parse_article(b"h<b>dog</b>\0W\0\0\0\x02ab", None).unwrap(),
+            (String::new(), "<b>dog</b>".to_string())
+        );
+    }
+}
@@ -0,0 +1,21 @@
+// Fixtures shared by the unit tests
+use rusqlite::Connection;
+
+use crate::dict::DictDb;
+use crate::migrations::migrate;
+
+// dict.db as created by releases before schema migrations, with a few rows of data
+pub fn v0_database() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(include_str!("../tests/fixtures/dict_v0.sql"))
+        .unwrap();
+    conn
+}
+
+// The v0 fixture brought up to date, with `sql` run on top of it to add test data
+pub fn test_db(sql: &str) -> DictDb {
+    let mut conn = v0_database();
+    migrate(&mut conn).unwrap();
+    conn.execute_batch(sql).unwrap();
+    DictDb::open(conn).unwrap()
+}
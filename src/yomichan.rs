@@ -0,0 +1,962 @@
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::pitch::is_high;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct YomichanDict {
+    pub(crate) format: Option<u8>,
+    pub(crate) version: Option<u8>,
+    pub(crate) tag_meta: Option<HashMap<String, TagMeta>>,
+    #[serde(default)]
+    pub revision: String,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub sequenced: bool,
+    // "rank-based" or "occurrence-based", for the frequencies in the meta banks
+    pub frequency_mode: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagMeta {
+    category: Option<String>,
+    order: Option<i64>,
+    notes: Option<String>,
+    score: Option<i64>,
+}
+
+// [name, category, order, notes, score]
+#[derive(Debug, Deserialize)]
+pub struct YomichanTag {
+    pub name: String,
+    pub category: String,
+    pub order: i64,
+    pub notes: String,
+    pub score: i64,
+}
+
+impl YomichanDict {
+    // older dictionaries use `version` instead of `format`
+    pub fn format(&self) -> u8 {
+        self.format.or(self.version).unwrap_or(1)
+    }
+
+    pub fn tags(&self) -> Vec<YomichanTag> {
+        let tag_meta = match &self.tag_meta {
+            Some(tag_meta) => tag_meta,
+            None => return vec![],
+        };
+        tag_meta
+            .iter()
+            .map(|(name, meta)| YomichanTag {
+                name: name.to_string(),
+                category: meta.category.clone().unwrap_or_default(),
+                order: meta.order.unwrap_or_default(),
+                notes: meta.notes.clone().unwrap_or_default(),
+                score: meta.score.unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+// Common representation of a term regardless of the term bank format it came from
+#[derive(Debug, Default)]
+pub struct TermEntry {
+    pub expression: String,
+    pub reading: String,
+    pub definition_tags: String,
+    pub rule_identifiers: String,
+    pub popularity: f64,
+    pub meanings: Vec<String>,
+    // the JSON of each meaning that isn't plain text, so it can be exported as it was
+    pub glossaries: Vec<Option<String>>,
+    pub sequence: Option<i64>,
+    pub term_tags: String,
+    // both scripts and numbered pinyin of Chinese terms
+    pub traditional: String,
+    pub simplified: String,
+    pub pinyin: String,
+}
+
+// [expression, reading, definition_tags, rule_identifiers, popularity, ...meanings]
+#[derive(Debug)]
+pub struct YomichanEntryV1 {
+    expression: String,
+    reading: String,
+    definition_tags: String,
+    rule_identifiers: String,
+    popularity: f64,
+    meanings: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct YomichanEntryV3 {
+    expression: String,
+    reading: String,
+    definition_tags: Option<String>,
+    rule_identifiers: String,
+    popularity: f64,
+    meanings: Vec<SourceGlossary>,
+    sequence: i64,
+    term_tags: String,
+}
+
+// A glossary along with the JSON it was parsed from
+#[derive(Debug)]
+pub struct SourceGlossary {
+    glossary: Glossary,
+    json: Value,
+}
+
+impl<'de> de::Deserialize<'de> for SourceGlossary {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let json = <Value as de::Deserialize>::deserialize(deserializer)?;
+        let glossary =
+            <Glossary as de::Deserialize>::deserialize(&json).map_err(de::Error::custom)?;
+        Ok(SourceGlossary { glossary, json })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Glossary {
+    Text(String),
+    // [uninflected term, [rules]], describes an inflection rather than a meaning
+    #[allow(dead_code)]
+    Deinflection(String, Vec<String>),
+    Content(GlossaryContent),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum GlossaryContent {
+    Text { text: String },
+    Image(Value),
+    StructuredContent { content: Value },
+}
+
+impl<'de> serde::Deserialize<'de> for YomichanEntryV1 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = YomichanEntryV1;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a version 1 term bank entry")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut next = |index: usize| -> Result<Value, A::Error> {
+                    seq.next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(index, &self))
+                };
+                let expression = next(0)?;
+                let reading = next(1)?;
+                let definition_tags = next(2)?;
+                let rule_identifiers = next(3)?;
+                let popularity = next(4)?;
+
+                let mut meanings = vec![];
+                while let Some(meaning) = seq.next_element::<String>()? {
+                    meanings.push(meaning);
+                }
+
+                let text = |value: Value| value.as_str().unwrap_or_default().to_string();
+                Ok(YomichanEntryV1 {
+                    expression: text(expression),
+                    reading: text(reading),
+                    definition_tags: text(definition_tags),
+                    rule_identifiers: text(rule_identifiers),
+                    popularity: popularity.as_f64().unwrap_or_default(),
+                    meanings,
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(EntryVisitor)
+    }
+}
+
+// [expression, mode, data], where mode is "freq" or "pitch"
+#[derive(Debug, Deserialize)]
+pub struct YomichanMetaEntry {
+    pub expression: String,
+    pub mode: String,
+    pub data: Value,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FrequencyData {
+    pub reading: Option<String>,
+    pub value: i64,
+    pub display: Option<String>,
+}
+
+// Frequency data is either a number, a string like "1234" or "1234㋕", a {value, displayValue}
+// object, or any of these wrapped in {reading, frequency} to target a single reading
+pub fn parse_frequency(data: &Value) -> Option<FrequencyData> {
+    match data {
+        Value::Number(value) => Some(FrequencyData {
+            reading: None,
+            value: value.as_f64()? as i64,
+            display: None,
+        }),
+        Value::String(text) => {
+            let digits = text
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>();
+            Some(FrequencyData {
+                reading: None,
+                value: digits.parse().ok()?,
+                display: Some(text.to_string()),
+            })
+        }
+        Value::Object(object) => {
+            if let Some(frequency) = object.get("frequency") {
+                let mut data = parse_frequency(frequency)?;
+                data.reading = object
+                    .get("reading")
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+                return Some(data);
+            }
+            let mut data = parse_frequency(object.get("value")?)?;
+            if let Some(display) = object.get("displayValue").and_then(Value::as_str) {
+                data.display = Some(display.to_string());
+            }
+            Some(data)
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PitchData {
+    pub reading: String,
+    pub pitches: Vec<PitchAccent>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PitchAccent {
+    pub position: PitchPosition,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+// Either the mora after which the pitch drops, or the whole pattern such as "LHHL"
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PitchPosition {
+    Downstep(usize),
+    Pattern(String),
+}
+
+impl PitchPosition {
+    // Patterns only map to a position when they have at most one downstep
+    pub fn downstep(&self) -> Option<usize> {
+        match self {
+            PitchPosition::Downstep(position) => Some(*position),
+            PitchPosition::Pattern(pattern) => {
+                if pattern.is_empty() || !pattern.chars().all(|c| c == 'H' || c == 'L') {
+                    return None;
+                }
+                (0..=pattern.len()).find(|&position| {
+                    pattern
+                        .chars()
+                        .enumerate()
+                        .all(|(mora, c)| is_high(position, mora) == (c == 'H'))
+                })
+            }
+        }
+    }
+}
+
+// version 1: [character, onyomi, kunyomi, tags, ...meanings]
+// version 3: [character, onyomi, kunyomi, tags, [meanings], {stats}]
+#[derive(Debug)]
+pub struct YomichanKanji {
+    pub character: String,
+    pub onyomi: String,
+    pub kunyomi: String,
+    pub tags: String,
+    pub meanings: Vec<String>,
+    pub stats: serde_json::Map<String, Value>,
+}
+
+impl<'de> serde::Deserialize<'de> for YomichanKanji {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KanjiVisitor;
+
+        impl<'de> Visitor<'de> for KanjiVisitor {
+            type Value = YomichanKanji;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a kanji bank entry")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut fields = vec![];
+                for index in 0..4 {
+                    let field: String = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(index, &self))?;
+                    fields.push(field);
+                }
+
+                let mut meanings = vec![];
+                let mut stats = serde_json::Map::new();
+                match seq.next_element::<Value>()? {
+                    Some(Value::Array(values)) => {
+                        meanings = values
+                            .iter()
+                            .filter_map(|value| value.as_str().map(str::to_string))
+                            .collect();
+                        if let Some(Value::Object(values)) = seq.next_element::<Value>()? {
+                            stats = values;
+                        }
+                    }
+                    Some(Value::String(meaning)) => {
+                        meanings.push(meaning);
+                        while let Some(meaning) = seq.next_element::<String>()? {
+                            meanings.push(meaning);
+                        }
+                    }
+                    _ => {}
+                }
+
+                let mut fields = fields.into_iter();
+                let mut next = || fields.next().unwrap_or_default();
+                Ok(YomichanKanji {
+                    character: next(),
+                    onyomi: next(),
+                    kunyomi: next(),
+                    tags: next(),
+                    meanings,
+                    stats,
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(KanjiVisitor)
+    }
+}
+
+impl From<YomichanEntryV1> for TermEntry {
+    fn from(entry: YomichanEntryV1) -> Self {
+        TermEntry {
+            expression: entry.expression,
+            reading: entry.reading,
+            definition_tags: entry.definition_tags,
+            rule_identifiers: entry.rule_identifiers,
+            popularity: entry.popularity,
+            meanings: entry.meanings,
+            sequence: None,
+            term_tags: String::new(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<YomichanEntryV3> for TermEntry {
+    fn from(entry: YomichanEntryV3) -> Self {
+        let mut meanings = vec![];
+        let mut glossaries = vec![];
+        for meaning in entry.meanings {
+            if let Some(html) = meaning.glossary.to_html() {
+                meanings.push(html);
+                glossaries.push(match meaning.glossary {
+                    Glossary::Text(_) => None,
+                    _ => Some(meaning.json.to_string()),
+                });
+            }
+        }
+        TermEntry {
+            expression: entry.expression,
+            reading: entry.reading,
+            definition_tags: entry.definition_tags.unwrap_or_default(),
+            rule_identifiers: entry.rule_identifiers,
+            popularity: entry.popularity,
+            meanings,
+            glossaries,
+            sequence: Some(entry.sequence),
+            term_tags: entry.term_tags,
+            ..Default::default()
+        }
+    }
+}
+
+impl Glossary {
+    pub fn to_html(&self) -> Option<String> {
+        match self {
+            Glossary::Text(text) => Some(text.to_string()),
+            Glossary::Deinflection(..) => None,
+            Glossary::Content(GlossaryContent::Text { text }) => Some(text.to_string()),
+            Glossary::Content(GlossaryContent::Image(image)) => {
+                let html = render_image(image);
+                (!html.is_empty()).then_some(html)
+            }
+            Glossary::Content(GlossaryContent::StructuredContent { content }) => {
+                Some(render_structured_content(content))
+            }
+        }
+    }
+}
+
+// Revisions are free-form ("jmdict4", "2023-01-01", "1.2.10"), so runs of digits are compared numerically
+pub fn compare_revisions(a: &str, b: &str) -> Ordering {
+    fn chunks(revision: &str) -> Vec<(Option<u64>, String)> {
+        let mut chunks: Vec<(Option<u64>, String)> = vec![];
+        for c in revision.chars() {
+            match chunks.last_mut() {
+                Some((_, chunk))
+                    if chunk.chars().all(|d| d.is_ascii_digit()) == c.is_ascii_digit() =>
+                {
+                    chunk.push(c)
+                }
+                _ => chunks.push((None, c.to_string())),
+            }
+        }
+        chunks
+            .into_iter()
+            .map(|(_, chunk)| (chunk.parse().ok(), chunk))
+            .collect()
+    }
+    chunks(a).cmp(&chunks(b))
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Meaning html as a single line of text for the terminal
+pub fn html_to_text(html: &str) -> String {
+    lazy_static! {
+        static ref BREAK: Regex = Regex::new(r"(?i)<br\s*/?>|</(li|div|p)>").unwrap();
+        static ref TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    // line breaks and the ends of list items separate glosses, semicolons in the text
+    // itself are left alone
+    let text = BREAK.replace_all(html, "\n");
+    let text = TAG.replace_all(&text, "");
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&");
+    text.lines()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+const CONTENT_TAGS: [&str; 17] = [
+    "ruby", "rt", "rp", "table", "thead", "tbody", "tfoot", "tr", "td", "th", "span", "div", "ol",
+    "ul", "li", "details", "summary",
+];
+
+// Renders yomichan structured content (https://github.com/FooSoft/yomichan/blob/master/ext/data/schemas/dictionary-term-bank-v3-schema.json)
+pub fn render_structured_content(content: &Value) -> String {
+    match content {
+        Value::String(text) => escape_html(text),
+        Value::Array(children) => children.iter().map(render_structured_content).collect(),
+        Value::Object(node) => {
+            let tag = node.get("tag").and_then(Value::as_str).unwrap_or_default();
+            let inner = node
+                .get("content")
+                .map(render_structured_content)
+                .unwrap_or_default();
+            match tag {
+                "br" => "<br>".to_string(),
+                "img" => render_image(content),
+                "a" => match node.get("href").and_then(Value::as_str) {
+                    // internal links point at yomichan searches, which mean nothing on a card
+                    Some(href) if href.starts_with("http") => {
+                        format!("<a href=\"{}\">{}</a>", escape_html(href), inner)
+                    }
+                    _ => inner,
+                },
+                tag if CONTENT_TAGS.contains(&tag) => {
+                    format!("<{0}{1}>{2}</{0}>", tag, render_attributes(node), inner)
+                }
+                _ => inner,
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+fn render_attributes(node: &serde_json::Map<String, Value>) -> String {
+    let mut attributes = String::new();
+
+    for (key, name) in [
+        ("lang", "lang"),
+        ("title", "title"),
+        ("colSpan", "colspan"),
+        ("rowSpan", "rowspan"),
+    ] {
+        if let Some(value) = node.get(key) {
+            let value = value
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| value.to_string());
+            attributes += &format!(" {}=\"{}\"", name, escape_html(&value));
+        }
+    }
+
+    if let Some(Value::Object(data)) = node.get("data") {
+        for (key, value) in data {
+            if let Some(value) = value.as_str() {
+                attributes += &format!(" data-sc-{}=\"{}\"", key, escape_html(value));
+            }
+        }
+    }
+
+    if let Some(Value::Object(style)) = node.get("style") {
+        let css = style
+            .iter()
+            .filter_map(|(key, value)| {
+                Some(format!("{}: {}", kebab_case(key), css_value(key, value)?))
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        if !css.is_empty() {
+            attributes += &format!(" style=\"{}\"", escape_html(&css));
+        }
+    }
+
+    attributes
+}
+
+fn kebab_case(key: &str) -> String {
+    let mut css = String::new();
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            css.push('-');
+            css.push(c.to_ascii_lowercase());
+        } else {
+            css.push(c);
+        }
+    }
+    css
+}
+
+fn css_value(key: &str, value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.to_string()),
+        // margins and paddings are given in em
+        Value::Number(value) if key.starts_with("margin") || key.starts_with("padding") => {
+            Some(format!("{}em", value))
+        }
+        Value::Number(value) => Some(value.to_string()),
+        Value::Array(values) => Some(
+            values
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        _ => None,
+    }
+}
+
+// Image files stay inside the dictionary, so only their textual description is kept
+fn render_image(image: &Value) -> String {
+    ["description", "alt", "title"]
+        .iter()
+        .find_map(|key| image.get(key).and_then(Value::as_str))
+        .map(escape_html)
+        .unwrap_or_default()
+}
+
+// A yomichan dictionary, either extracted to a directory or still packed in its zip archive
+pub enum YomichanSource {
+    Directory(PathBuf),
+    Archive(ZipArchive<BufReader<File>>),
+}
+
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+// term_bank_12.json -> Some(12)
+fn bank_number(name: &str, prefix: &str) -> Option<usize> {
+    file_name(name)
+        .strip_prefix(prefix)?
+        .strip_suffix(".json")?
+        .parse()
+        .ok()
+}
+
+impl YomichanSource {
+    pub fn open(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(YomichanSource::Directory(path.to_path_buf()));
+        }
+
+        if !path.is_file() {
+            bail!("{} does not exist", path.display());
+        }
+
+        let file = File::open(path)?;
+        let archive = ZipArchive::new(BufReader::new(file))
+            .with_context(|| format!("{} is not a zip archive", path.display()))?;
+        Ok(YomichanSource::Archive(archive))
+    }
+
+    fn entry_names(&self) -> Result<Vec<String>> {
+        match self {
+            YomichanSource::Directory(dir) => {
+                let mut names = vec![];
+                for entry in fs::read_dir(dir)? {
+                    if let Some(name) = entry?.file_name().to_str() {
+                        names.push(name.to_string());
+                    }
+                }
+                Ok(names)
+            }
+            YomichanSource::Archive(archive) => {
+                Ok(archive.file_names().map(|name| name.to_string()).collect())
+            }
+        }
+    }
+
+    // Some archives nest everything under a top level folder, so entries are matched by file name
+    fn find(&self, name: &str) -> Option<String> {
+        self.entry_names()
+            .ok()?
+            .into_iter()
+            .find(|entry| file_name(entry) == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.find(name).is_some()
+    }
+
+    // Returns the entries named `{prefix}N.json`, ordered by N
+    pub fn banks(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut banks = self
+            .entry_names()?
+            .into_iter()
+            .filter_map(|name| bank_number(&name, prefix).map(|number| (number, name)))
+            .collect::<Vec<_>>();
+        banks.sort();
+        Ok(banks.into_iter().map(|(_, name)| name).collect())
+    }
+
+    pub fn read_index(&mut self) -> Result<YomichanDict> {
+        let index = self.find("index.json").context("Missing index.json")?;
+        Ok(serde_json::from_reader(self.read(&index)?)?)
+    }
+
+    // Uncompressed size of an entry in bytes, used to report progress
+    pub fn size(&mut self, name: &str) -> Result<u64> {
+        match self {
+            YomichanSource::Directory(dir) => Ok(fs::metadata(dir.join(name))?.len()),
+            YomichanSource::Archive(archive) => Ok(archive.by_name(name)?.size()),
+        }
+    }
+
+    pub fn read(&mut self, name: &str) -> Result<Box<dyn Read + '_>> {
+        match self {
+            YomichanSource::Directory(dir) => {
+                let file = File::open(dir.join(name))
+                    .with_context(|| format!("Failed to open {}", name))?;
+                Ok(Box::new(BufReader::new(file)))
+            }
+            YomichanSource::Archive(archive) => {
+                let entry = archive
+                    .by_name(name)
+                    .with_context(|| format!("Failed to read {} from the archive", name))?;
+                Ok(Box::new(BufReader::new(entry)))
+            }
+        }
+    }
+}
+
+// Rows per bank, like the dictionaries yomichan's own tools produce
+const BANK_SIZE: usize = 10000;
+
+// Writes a dictionary archive that yomichan (and ace) can import
+pub struct YomichanWriter {
+    zip: ZipWriter<File>,
+}
+
+impl YomichanWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(YomichanWriter {
+            zip: ZipWriter::new(file),
+        })
+    }
+
+    pub fn write_json(&mut self, name: &str, value: &Value) -> Result<()> {
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(name, options)?;
+        serde_json::to_writer(&mut self.zip, value)?;
+        Ok(())
+    }
+
+    pub fn banks(&mut self, prefix: &'static str) -> BankWriter<'_> {
+        BankWriter {
+            writer: self,
+            prefix,
+            rows: vec![],
+            written: 0,
+        }
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
+
+// Collects rows into numbered banks, `{prefix}1.json` and onwards
+pub struct BankWriter<'a> {
+    writer: &'a mut YomichanWriter,
+    prefix: &'static str,
+    rows: Vec<Value>,
+    written: usize,
+}
+
+impl BankWriter<'_> {
+    pub fn push(&mut self, row: Value) -> Result<()> {
+        self.rows.push(row);
+        if self.rows.len() == BANK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let rows = Value::Array(std::mem::take(&mut self.rows));
+        self.written += 1;
+        let name = format!("{}{}.json", self.prefix, self.written);
+        self.writer.write_json(&name, &rows)
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if !self.rows.is_empty() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+struct BankVisitor<T, F> {
+    callback: F,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T, F> Visitor<'de> for BankVisitor<T, F>
+where
+    T: serde::Deserialize<'de>,
+    F: FnMut(T) -> Result<()>,
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of bank rows")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(row) = seq.next_element()? {
+            (self.callback)(row).map_err(|err| de::Error::custom(format!("{:#}", err)))?;
+        }
+        Ok(())
+    }
+}
+
+// Hands the rows of a bank to `callback` one at a time as they are parsed,
+// so memory use does not grow with the size of the bank
+pub fn stream_bank<T, R, F>(reader: R, callback: F) -> Result<()>
+where
+    T: DeserializeOwned,
+    R: Read,
+    F: FnMut(T) -> Result<()>,
+{
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    deserializer.deserialize_seq(BankVisitor {
+        callback,
+        marker: PhantomData,
+    })?;
+    deserializer.end()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compare_revisions, html_to_text, parse_frequency, render_structured_content, stream_bank,
+        FrequencyData, PitchData, PitchPosition, TermEntry, YomichanEntryV1, YomichanEntryV3,
+        YomichanKanji,
+    };
+    use std::cmp::Ordering;
+
+    #[test]
+    fn parses_term_bank_formats() {
+        let v1: YomichanEntryV1 =
+            serde_json::from_str(r#"["読む","よむ","v5","v5",0,"to read","to recite"]"#).unwrap();
+        let v1 = TermEntry::from(v1);
+        assert_eq!(v1.meanings, vec!["to read", "to recite"]);
+        assert_eq!(v1.sequence, None);
+
+        let v3: YomichanEntryV3 = serde_json::from_str(
+            r#"["読む","よむ",null,"v5",0,["to read",{"type":"text","text":"to recite"},["読む",["v5"]]],42,""]"#,
+        )
+        .unwrap();
+        let v3 = TermEntry::from(v3);
+        assert_eq!(v3.meanings, vec!["to read", "to recite"]);
+        // only the glossaries that aren't plain text keep their JSON for exports
+        assert_eq!(
+            v3.glossaries,
+            vec![None, Some(r#"{"text":"to recite","type":"text"}"#.to_string())]
+        );
+        assert_eq!(v3.sequence, Some(42));
+    }
+
+    #[test]
+    fn streams_bank_rows() {
+        let bank = r#"[["日本", "にほん", "", "", 0, ["Japan"], 1, ""], ["本", "ほん", "", "", 0, ["book"], 2, ""]]"#;
+        let mut expressions = vec![];
+        stream_bank(bank.as_bytes(), |entry: YomichanEntryV3| {
+            expressions.push(TermEntry::from(entry).expression);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(expressions, vec!["日本", "本"]);
+
+        // errors from the callback stop the import
+        let res = stream_bank(bank.as_bytes(), |_: YomichanEntryV3| {
+            anyhow::bail!("database is locked")
+        });
+        assert!(res.unwrap_err().to_string().contains("database is locked"));
+        assert!(stream_bank("[1] 2".as_bytes(), |_: i64| Ok(())).is_err());
+    }
+
+    #[test]
+    fn parses_frequency_formats() {
+        let parse = |data: &str| parse_frequency(&serde_json::from_str(data).unwrap()).unwrap();
+        let data = |reading: Option<&str>, value, display: Option<&str>| FrequencyData {
+            reading: reading.map(str::to_string),
+            value,
+            display: display.map(str::to_string),
+        };
+
+        assert_eq!(parse("120"), data(None, 120, None));
+        assert_eq!(parse(r#""120㋕""#), data(None, 120, Some("120㋕")));
+        assert_eq!(
+            parse(r#"{"value": 120, "displayValue": "120 (top)"}"#),
+            data(None, 120, Some("120 (top)"))
+        );
+        assert_eq!(
+            parse(r#"{"reading": "はし", "frequency": 120}"#),
+            data(Some("はし"), 120, None)
+        );
+        assert_eq!(
+            parse(r#"{"reading": "はし", "frequency": {"value": 120, "displayValue": "120"}}"#),
+            data(Some("はし"), 120, Some("120"))
+        );
+    }
+
+    #[test]
+    fn parses_pitch_positions() {
+        let pitch: PitchData = serde_json::from_str(
+            r#"{"reading":"はし","pitches":[{"position":1},{"position":"LHL"},{"position":"LHH"},{"position":"HLH"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            pitch.pitches[1].position,
+            PitchPosition::Pattern("LHL".to_string())
+        );
+        let positions = pitch
+            .pitches
+            .iter()
+            .map(|accent| accent.position.downstep())
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![Some(1), Some(2), Some(0), None]);
+    }
+
+    #[test]
+    fn parses_kanji_bank_formats() {
+        let v1: YomichanKanji =
+            serde_json::from_str(r#"["食","ショク ジキ","く.う た.べる","jouyou","eat","food"]"#)
+                .unwrap();
+        assert_eq!(v1.meanings, vec!["eat", "food"]);
+
+        let v3: YomichanKanji = serde_json::from_str(
+            r#"["食","ショク ジキ","く.う た.べる","jouyou",["eat","food"],{"strokes":"9"}]"#,
+        )
+        .unwrap();
+        assert_eq!(v3.kunyomi, "く.う た.べる");
+        assert_eq!(v3.meanings, vec!["eat", "food"]);
+        assert_eq!(v3.stats["strokes"], "9");
+    }
+
+    #[test]
+    fn compares_revisions() {
+        assert_eq!(compare_revisions("jmdict4", "jmdict10"), Ordering::Less);
+        assert_eq!(
+            compare_revisions("2023-01-02", "2023-01-01"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_revisions("1.2", "1.2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn renders_structured_content() {
+        let content = serde_json::json!([
+            {"tag": "span", "data": {"content": "pos"}, "style": {"fontSize": "0.8em", "marginLeft": 0.5}, "content": "noun"},
+            {"tag": "ul", "content": [{"tag": "li", "content": "cat & dog"}, {"tag": "br"}]},
+            {"tag": "img", "path": "img/cat.png", "title": "a cat"},
+            {"tag": "a", "href": "?query=猫", "content": "猫"}
+        ]);
+        assert_eq!(
+            render_structured_content(&content),
+            "<span data-sc-content=\"pos\" style=\"font-size: 0.8em; margin-left: 0.5em\">noun</span>\
+             <ul><li>cat &amp; dog</li><br></ul>a cat猫"
+        );
+    }
+
+    #[test]
+    fn joins_glosses_into_one_line() {
+        assert_eq!(
+            html_to_text("<ul><li>to eat (e.g. rice; bread)</li><li>to live on</li></ul>"),
+            "to eat (e.g. rice; bread); to live on"
+        );
+        assert_eq!(
+            html_to_text("bridge;  span<br><br>&lt;b&gt; &amp; c"),
+            "bridge;  span; <b> & c"
+        );
+    }
+}
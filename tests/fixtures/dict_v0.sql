@@ -0,0 +1,34 @@
+-- dict.db as created by releases before schema migrations, with a few rows of data
+CREATE TABLE IF NOT EXISTS dicts (
+      id              INTEGER PRIMARY KEY AUTOINCREMENT,
+      title           TEXT NOT NULL,
+      priority        INTEGER DEFAULT 0,
+      fallback        INTEGER DEFAULT 0,
+      enabled         INTEGER DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS entries (
+      id              INTEGER PRIMARY KEY AUTOINCREMENT,
+      expression      TEXT NOT NULL,
+      reading         TEXT,
+      meaning         TEXT NOT NULL,
+      dict_id         INTEGER NOT NULL,
+      frequency       INTEGER DEFAULT 0,
+      FOREIGN KEY(dict_id) REFERENCES dicts(id)
+);
+
+CREATE TABLE IF NOT EXISTS freq (
+      id              INTEGER PRIMARY KEY AUTOINCREMENT,
+      word            TEXT NOT NULL UNIQUE,
+      freq            INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS word_idx ON entries(expression);
+
+INSERT INTO dicts (title, priority, fallback, enabled) VALUES ('jmdict', 10, 0, 1);
+
+INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('箸', 'はし', 'chopsticks', 1);
+INSERT INTO entries (expression, reading, meaning, dict_id) VALUES ('橋', 'はし', 'bridge', 1);
+
+INSERT INTO freq (word, freq) VALUES ('箸', 4500);
+INSERT INTO freq (word, freq) VALUES ('橋', 300);